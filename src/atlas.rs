@@ -1,175 +1,442 @@
+use ab_glyph::{point, Font as _, GlyphId};
 use sdl2::{
     pixels::{Color, PixelFormatEnum},
     rect::Rect,
-    render::{Canvas, Texture, TextureCreator},
-    ttf::{Font, Sdl2TtfContext},
+    render::{BlendMode, Canvas, Texture, TextureCreator},
+    surface::Surface,
+    ttf::Font,
     video::{Window, WindowContext},
 };
 use std::collections::HashMap;
 
-pub struct FontAtlas<'a> {
-    pub texture: Texture<'a>,
-    pub atlas: HashMap<char, Rect>,
+use crate::shape::{self, Face, ShapedGlyph};
+
+/// Side of a square atlas page, in pixels.
+const PAGE_SIZE: u32 = 1024;
+/// Pages kept at most. Past this, the least recently used page is wiped
+/// and its glyphs are rasterized again on their next use.
+const MAX_PAGES: usize = 4;
+
+/// Identifies a rasterized glyph. There is no color: glyphs are drawn
+/// white and tinted when copied, so one entry serves every color.
+#[derive(Hash, Eq, PartialEq, Clone)]
+pub struct TextureInfo {
+    /// Name of the face the glyph comes from. Empty for the replacement box.
+    pub font_name: String,
+    pub font_size: u16,
+    /// Glyph ID in the face, or the codepoint for the replacement box.
+    pub glyph: u32,
 }
 
-impl<'a> FontAtlas<'a> {
-    pub fn new(
-        tc: &'a mut TextureCreator<WindowContext>,
-        canvas: &mut Canvas<Window>,
-        font: &'a Font,
-    ) -> FontAtlas<'a> {
-        let mut atlas = HashMap::new();
-        let mut x: i32 = 0;
-        let y: i32 = 0;
-        let mut tex_list = Vec::new();
-
-        // Generate all the textures and create all the rects
-        for code in 1..255 as u8 {
-            let font_render = font
-                .render_char(code as char)
-                .blended(Color::RGBA(255, 255, 255, 255));
-
-            match font_render {
-                Ok(_) => (),
-                Err(_) => continue,
-            }
+/// Where a glyph was packed, and where its bitmap goes relative to the
+/// pen position on the top of the line.
+#[derive(Clone, Copy)]
+struct Glyph {
+    page: usize,
+    rect: Rect,
+    left: i32,
+    top: i32,
+}
 
-            let texture = tc
-                .create_texture_from_surface(font_render.unwrap())
-                .unwrap();
-            let query = texture.query();
-            tex_list.push(texture);
+/// A row of glyphs of about the same height, filled left to right.
+struct Shelf {
+    y: u32,
+    height: u32,
+    width: u32,
+}
 
-            let rect = Rect::new(x, y, query.width, query.height);
-            x += query.width as i32;
-            atlas.insert(code as char, rect);
-        }
+/// A glyph of a `TextRun`: where to copy it from and to, and its tint.
+struct Quad {
+    face: Option<usize>,
+    id: u32,
+    /// Pen position the glyph was laid out at.
+    x: i32,
+    y: i32,
+    color: Color,
+    page: usize,
+    src: Rect,
+    dst: Rect,
+}
 
-        // Generate the final "atlas" texture
-        let mut final_texture = tc
-            .create_texture_target(
-                tc.default_pixel_format(),
-                atlas.values().into_iter().map(|v| v.width()).sum(),
-                atlas
-                    .values()
-                    .into_iter()
-                    .map(|v| v.height())
-                    .max()
-                    .unwrap(),
-            )
-            .unwrap();
-
-        canvas
-            .with_texture_canvas(&mut final_texture, |tcanvas| {
-                let mut x: i32 = 0;
-                for t in tex_list {
-                    let y: i32 = 0;
-                    let q = t.query();
-                    let w = q.width;
-                    let h = q.height;
-
-                    tcanvas.copy(&t, None, Some(Rect::new(x, y, w, h))).unwrap();
-                    x += w as i32;
-                }
-            })
-            .unwrap();
+/// One row of styled text laid out into glyph quads, drawn all at once by
+/// `FontAtlas::draw_run`.
+pub struct TextRun {
+    /// Pen position, where the next `push_str` goes.
+    x: i32,
+    y: i32,
+    quads: Vec<Quad>,
+    /// `FontAtlas::evictions` when the run was started.
+    evictions: u64,
+}
 
-        FontAtlas {
-            texture: final_texture,
-            atlas,
-        }
-    }
+struct Page<'fa> {
+    texture: Texture<'fa>,
+    shelves: Vec<Shelf>,
+    /// Value of the atlas clock when a glyph on this page was last drawn.
+    last_used: u64,
 }
 
-#[derive(Hash, Eq, PartialEq, Clone)]
-pub struct TextureInfo {
-    pub font_name: String,
-    pub font_size: i32,
-    pub fg: Color,
-    pub ch: char,
+impl Page<'_> {
+    /// Finds room for a `w` x `h` glyph on the tightest shelf it fits,
+    /// opening a new shelf below the others when none does.
+    fn allocate(&mut self, w: u32, h: u32) -> Option<Rect> {
+        let shelf = self
+            .shelves
+            .iter_mut()
+            .filter(|s| s.height >= h && s.height <= h + h / 4 && s.width + w <= PAGE_SIZE)
+            .min_by_key(|s| s.height);
+        if let Some(shelf) = shelf {
+            let rect = Rect::new(shelf.width as i32, shelf.y as i32, w, h);
+            shelf.width += w;
+            return Some(rect);
+        }
+        let y = self.shelves.last().map_or(0, |s| s.y + s.height);
+        if y + h > PAGE_SIZE || w > PAGE_SIZE {
+            return None;
+        }
+        self.shelves.push(Shelf {
+            y,
+            height: h,
+            width: w,
+        });
+        Some(Rect::new(0, y as i32, w, h))
+    }
 }
 
-pub struct FontAtlas2<'fa> {
-    pub atlas: HashMap<TextureInfo, Texture<'fa>>,
+/// Glyph cache for every font and size in use. Glyphs are rasterized on
+/// first use and packed into a few large page textures, so drawing one is
+/// a copy out of a page and the texture count stays bounded however many
+/// sizes and colors get drawn.
+pub struct FontAtlas<'fa> {
+    glyphs: HashMap<TextureInfo, Glyph>,
+    pages: Vec<Page<'fa>>,
+    /// Counts glyph lookups, to tell which page was used least recently.
+    clock: u64,
+    /// Pages wiped so far. A run laid out before an eviction may point at
+    /// glyphs that are gone.
+    evictions: u64,
     tc: &'fa TextureCreator<WindowContext>,
+    /// The primary face, then the fallbacks tried in order for chars it
+    /// has no glyph for.
+    faces: Vec<Face>,
+    /// In points.
+    size: u16,
 }
 
-impl<'fa> FontAtlas2<'fa> {
+impl<'fa> FontAtlas<'fa> {
     pub fn new(tc: &'fa TextureCreator<WindowContext>) -> Self {
-        FontAtlas2 {
-            atlas: HashMap::new(),
+        FontAtlas {
+            glyphs: HashMap::new(),
+            pages: Vec::new(),
+            clock: 0,
+            evictions: 0,
             tc,
+            faces: Vec::new(),
+            size: 14,
         }
     }
-    pub fn generate_new_texture(&mut self, font: &Font, te: TextureInfo) -> &Texture {
-        let surf = font.render_char(te.ch as char).blended(te.fg).unwrap();
 
-        let tex: Texture<'fa> = self.tc.create_texture_from_surface(surf).unwrap();
+    /// Replaces the faces text is shaped and drawn with, the primary one
+    /// first.
+    pub fn set_faces(&mut self, faces: Vec<Face>) {
+        self.faces = faces;
+    }
+
+    pub fn set_size(&mut self, size: u16) {
+        self.size = size;
+    }
 
-        self.atlas.insert(te.clone(), tex);
-        self.atlas.get(&te).unwrap()
+    /// Positions the glyphs of `text`, see `shape::shape`.
+    pub fn shape(&self, text: &str) -> Vec<ShapedGlyph> {
+        shape::shape(&self.faces, self.size, text)
     }
 
-    pub fn draw_char(&mut self, font: &Font, ch: char, fg: Color) -> &Texture {
-        let font_name = font.face_family_name().unwrap();
+    /// Width of `ch` in the primary face, rounded to pixels.
+    pub fn advance(&self, ch: char) -> u32 {
+        self.faces.first().map_or(0, |f| {
+            (f.font.h_advance_unscaled(f.font.glyph_id(ch)) * f.scale(self.size)).round() as u32
+        })
+    }
+
+    /// Room for a `w` x `h` glyph: on a page with space left, on a new
+    /// page, or on the least recently used page after wiping it. `None`
+    /// for glyphs bigger than a page, which huge font sizes make.
+    fn allocate(&mut self, w: u32, h: u32) -> Option<(usize, Rect)> {
+        if w > PAGE_SIZE || h > PAGE_SIZE {
+            return None;
+        }
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            if let Some(rect) = page.allocate(w, h) {
+                return Some((i, rect));
+            }
+        }
+        let i = if self.pages.len() < MAX_PAGES {
+            let mut texture = self
+                .tc
+                .create_texture_static(PixelFormatEnum::ARGB8888, PAGE_SIZE, PAGE_SIZE)
+                .unwrap();
+            texture.set_blend_mode(BlendMode::Blend);
+            self.pages.push(Page {
+                texture,
+                shelves: Vec::new(),
+                last_used: self.clock,
+            });
+            self.pages.len() - 1
+        } else {
+            let (i, page) = self
+                .pages
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, p)| p.last_used)
+                .unwrap();
+            page.shelves.clear();
+            self.glyphs.retain(|_, g| g.page != i);
+            self.evictions += 1;
+            i
+        };
+        // Fits, the page is empty
+        let rect = self.pages[i].allocate(w, h)?;
+        Some((i, rect))
+    }
+
+    /// A glyph that draws nothing, for spaces and for glyphs too big for
+    /// the atlas.
+    fn blank_glyph(&mut self) -> Glyph {
+        let (page, rect) = self.allocate(1, 1).unwrap();
+        self.pages[page].texture.update(rect, &[0; 4], 4).unwrap();
+        Glyph {
+            page,
+            rect,
+            left: 0,
+            top: 0,
+        }
+    }
+
+    /// Rasterizes glyph `id` of `face` in white, into ARGB8888 pixels.
+    /// Returns the pixels, their size, and their offset from the pen on the
+    /// top of the line. `None` for glyphs with nothing to draw.
+    fn rasterize(&self, face: &Face, id: u32) -> Option<(Vec<u8>, u32, u32, i32, i32)> {
+        let scale = face.px_scale(self.size);
+        let ascent = face.font.ascent_unscaled() * face.scale(self.size);
+        let glyph = GlyphId(id as u16).with_scale_and_position(scale, point(0.0, ascent));
+        let outline = face.font.outline_glyph(glyph)?;
+        let bounds = outline.px_bounds();
+        let (w, h) = (bounds.width() as u32, bounds.height() as u32);
+        let mut pixels = vec![0; (w * h * 4) as usize];
+        outline.draw(|x, y, coverage| {
+            let i = ((y * w + x) * 4) as usize;
+            // White, so only the alpha byte varies
+            pixels[i..i + 4].copy_from_slice(&[255, 255, 255, (coverage * 255.0) as u8]);
+        });
+        Some((pixels, w, h, bounds.min.x as i32, bounds.min.y as i32))
+    }
+
+    fn generate_new_glyph(&mut self, font: &Font, face: Option<usize>, te: TextureInfo) -> Glyph {
+        let raster = face.and_then(|i| self.rasterize(&self.faces[i], te.glyph));
+        let glyph = match raster {
+            Some((pixels, w, h, left, top)) => match self.allocate(w.max(1), h.max(1)) {
+                Some((page, rect)) => {
+                    if w > 0 && h > 0 {
+                        self.pages[page]
+                            .texture
+                            .update(rect, &pixels, w as usize * 4)
+                            .unwrap();
+                    }
+                    Glyph {
+                        page,
+                        rect,
+                        left,
+                        top,
+                    }
+                }
+                None => self.blank_glyph(),
+            },
+            // Blank glyphs, such as spaces, draw nothing
+            None if face.is_some() => self.blank_glyph(),
+            None => {
+                let ch = char::from_u32(te.glyph).unwrap_or(char::REPLACEMENT_CHARACTER);
+                let surf = replacement_box(font, ch)
+                    .convert_format(PixelFormatEnum::ARGB8888)
+                    .unwrap();
+                let (w, h) = surf.size();
+                match self.allocate(w, h) {
+                    Some((page, rect)) => {
+                        let texture = &mut self.pages[page].texture;
+                        surf.with_lock(|pixels| {
+                            texture.update(rect, pixels, surf.pitch() as usize)
+                        })
+                        .unwrap();
+                        Glyph {
+                            page,
+                            rect,
+                            left: 0,
+                            top: 0,
+                        }
+                    }
+                    None => self.blank_glyph(),
+                }
+            }
+        };
+        self.glyphs.insert(te, glyph);
+        glyph
+    }
+
+    fn glyph(&mut self, font: &Font, face: Option<usize>, id: u32) -> Glyph {
         let te = TextureInfo {
-            font_name,
-            fg,
-            ch,
-            font_size: font.height(),
+            font_name: face.map_or(String::new(), |i| self.faces[i].name.clone()),
+            font_size: self.size,
+            glyph: id,
         };
+        let glyph = match self.glyphs.get(&te) {
+            Some(glyph) => *glyph,
+            None => self.generate_new_glyph(font, face, te),
+        };
+        self.clock += 1;
+        self.pages[glyph.page].last_used = self.clock;
+        glyph
+    }
 
-        let mut new = false;
-        if let None = self.atlas.get(&te) {
-            new = true
+    /// An empty run starting at `x`, `y`.
+    pub fn run(&self, x: i32, y: i32) -> TextRun {
+        TextRun {
+            x,
+            y,
+            quads: Vec::new(),
+            evictions: self.evictions,
         }
-        if new {
-            self.generate_new_texture(font, te)
-        } else {
-            self.atlas.get(&te).unwrap()
+    }
+
+    /// Lays out a shaped glyph, positioned from the pen position of `run`.
+    pub fn push_glyph(&mut self, run: &mut TextRun, font: &Font, g: &ShapedGlyph, fg: Color) {
+        let x = run.x + (g.x + g.x_offset).round() as i32;
+        let y = run.y - g.y_offset.round() as i32;
+        let glyph = self.glyph(font, g.face, g.id);
+        run.quads.push(Quad {
+            face: g.face,
+            id: g.id,
+            x,
+            y,
+            color: fg,
+            page: glyph.page,
+            src: glyph.rect,
+            dst: Rect::new(
+                x + glyph.left,
+                y + glyph.top,
+                glyph.rect.width(),
+                glyph.rect.height(),
+            ),
+        });
+    }
+
+    /// Shapes `text` and lays it out at the end of `run`. Returns its width.
+    pub fn push_str(&mut self, run: &mut TextRun, font: &Font, text: &str, fg: Color) -> u32 {
+        let glyphs = self.shape(text);
+        for g in &glyphs {
+            self.push_glyph(run, font, g, fg);
         }
+        let width = glyphs.last().map_or(0.0, |g| g.x + g.advance).round() as u32;
+        run.x += width as i32;
+        width
     }
 
-    pub fn draw_string(
-        &mut self,
-        s: String,
-        canvas: &mut Canvas<Window>,
-        font: &Font,
-        fg: Color,
-    ) -> Texture {
-        let mut x = 0;
-        // let mut y = 0;
-
-        let mut tw = 0;
-        let mut th = 0;
-
-        //FIXME this is stupid has we need to traverse the string twice FIXME
-        for c in s.chars() {
-            let ch = c as char;
-            let t = self.draw_char(font, ch, fg);
-            tw += t.query().width;
-            th = t.query().height;
+    /// Copies every glyph of `run` to the canvas and empties it. Quads go out grouped by
+    /// page and color, so consecutive copies share a texture and tint and
+    /// SDL can batch them into a few draw calls.
+    pub fn draw_run(&mut self, canvas: &mut Canvas<Window>, font: &Font, run: &mut TextRun) {
+        if run.evictions != self.evictions {
+            // Some glyphs may have been wiped while laying out, look them
+            // all up again and draw them one by one
+            for quad in &run.quads {
+                let glyph = self.glyph(font, quad.face, quad.id);
+                let texture = &mut self.pages[glyph.page].texture;
+                texture.set_color_mod(quad.color.r, quad.color.g, quad.color.b);
+                texture.set_alpha_mod(quad.color.a);
+                let dst = Rect::new(
+                    quad.x + glyph.left,
+                    quad.y + glyph.top,
+                    glyph.rect.width(),
+                    glyph.rect.height(),
+                );
+                canvas.copy(texture, glyph.rect, dst).unwrap();
+            }
+            run.quads.clear();
+            return;
+        }
+        run.quads.sort_by_key(|q| (q.page, q.color.rgba()));
+        let mut tint = None;
+        for quad in &run.quads {
+            let texture = &mut self.pages[quad.page].texture;
+            if tint != Some((quad.page, quad.color)) {
+                tint = Some((quad.page, quad.color));
+                texture.set_color_mod(quad.color.r, quad.color.g, quad.color.b);
+                texture.set_alpha_mod(quad.color.a);
+            }
+            canvas.copy(texture, quad.src, quad.dst).unwrap();
         }
+        run.quads.clear();
+    }
+}
 
-        let mut final_tex = self
-            .tc
-            .create_texture_target(PixelFormatEnum::RGBA8888, tw, th)
-            .unwrap();
+/// 3x5 pixel hex digits, row by row from the top bit.
+const HEX_DIGITS: [u16; 16] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_001_001_001,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+    0b111_101_111_101_101,
+    0b110_101_110_101_110,
+    0b111_100_100_100_111,
+    0b110_101_101_101_110,
+    0b111_100_111_100_111,
+    0b111_100_111_100_100,
+];
 
-        canvas
-            .with_texture_canvas(&mut final_tex, |texture_canvas| {
-                for c in s.chars() {
-                    let ch = c as char;
-                    let t = self.draw_char(font, ch, fg);
+/// Outlined box with the codepoint of `ch` in hex, two rows of digits,
+/// for chars none of the fonts can draw. As tall as a line of `font` and
+/// at least as wide as one of its chars. Drawn white like the glyphs.
+fn replacement_box(font: &Font, ch: char) -> Surface<'static> {
+    let digits: Vec<usize> = format!("{:04X}", ch as u32)
+        .chars()
+        .map(|d| d.to_digit(16).unwrap() as usize)
+        .collect();
+    let cols = digits.len().div_ceil(2) as i32;
+    let advance = font.size_of_char('0').map_or(0, |(w, _)| w as i32);
+    let w = replacement_width(advance, ch);
+    let h = font.height().max(15);
 
-                    texture_canvas
-                        .copy(&t, None, Rect::new(x, 0, t.query().width, t.query().height))
-                        .unwrap();
-                    x += t.query().width as i32;
-                }
-            })
-            .unwrap();
+    let mut surf = Surface::new(w as u32, h as u32, PixelFormatEnum::RGBA32).unwrap();
+    let outline = [
+        Rect::new(0, 0, w as u32, 1),
+        Rect::new(0, h - 1, w as u32, 1),
+        Rect::new(0, 0, 1, h as u32),
+        Rect::new(w - 1, 0, 1, h as u32),
+    ];
+    surf.fill_rects(&outline, Color::WHITE).unwrap();
 
-        final_tex
+    let left = (w - (cols * 4 - 1)) / 2;
+    let top = (h - 11) / 2;
+    for (i, digit) in digits.into_iter().enumerate() {
+        let (row, col) = (i as i32 / cols, i as i32 % cols);
+        for bit in 0..15 {
+            if HEX_DIGITS[digit] & (1 << (14 - bit)) != 0 {
+                let x = left + col * 4 + bit % 3;
+                let y = top + row * 6 + bit / 3;
+                surf.fill_rect(Rect::new(x, y, 1, 1), Color::WHITE).unwrap();
+            }
+        }
     }
+    surf
+}
+
+/// Width of the replacement box for `ch` next to chars `advance` wide:
+/// room for half its hex digits, side by side, and the outline.
+pub fn replacement_width(advance: i32, ch: char) -> i32 {
+    let cols = format!("{:04X}", ch as u32).len().div_ceil(2) as i32;
+    advance.max(cols * 4 + 3)
 }
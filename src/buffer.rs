@@ -0,0 +1,308 @@
+use std::{borrow::Cow, cell::RefCell, fmt::Display, ops::Range, rc::Rc};
+
+use ropey::{iter::Lines, Rope, RopeSlice};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::encoding::FileEncoding;
+
+/// One user-perceived character of a line: a base char and whatever
+/// combines with it, such as accents, skin-tone modifiers or the other
+/// half of a flag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grapheme {
+    /// Char column it starts at.
+    pub col: usize,
+    /// Chars in it.
+    pub len: usize,
+    /// Cells it takes on screen: two for wide chars such as CJK, up to the
+    /// next tab stop for tabs, and never less than one, so the line break
+    /// can hold the cursor.
+    pub width: usize,
+}
+
+/// Line index, tab width and the graphemes of the line.
+type GraphemeCache = (usize, usize, Rc<[Grapheme]>);
+
+/// How lines end in a file. Buffers always hold `\n` and put the file's
+/// style back when written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    /// The style most lines of `text` end in, LF when there are none.
+    pub fn detect(text: &str) -> LineEnding {
+        let crlf = text.matches("\r\n").count();
+        let cr = text.matches('\r').count() - crlf;
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > lf && crlf >= cr {
+            LineEnding::Crlf
+        } else if cr > lf {
+            LineEnding::Cr
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    /// The style after this one, to cycle through them.
+    pub fn next(self) -> LineEnding {
+        match self {
+            LineEnding::Lf => LineEnding::Crlf,
+            LineEnding::Crlf => LineEnding::Cr,
+            LineEnding::Cr => LineEnding::Lf,
+        }
+    }
+}
+
+impl Display for LineEnding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+            LineEnding::Cr => "CR",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// `text` with every CRLF and lone CR turned into LF.
+pub fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Text storage behind a `TextArea`.
+///
+/// Backed by a rope, so inserting, removing and looking up lines are all
+/// O(log n) no matter how large the document is. All positions are char
+/// indices, never byte offsets.
+///
+/// The rope's internal nodes carry line break counts that are updated on
+/// every insert and remove, so they double as the line-start index: going
+/// from a line to its first char (and back) is a tree descent, not a scan.
+pub struct Buffer {
+    rope: Rope,
+    /// Style lines are written with. Files mixing styles get the one most
+    /// of their lines use.
+    pub line_ending: LineEnding,
+    /// Encoding the text is saved in.
+    pub encoding: FileEncoding,
+    /// First line touched by an edit since the last `take_changed_from`.
+    changed_from: Option<usize>,
+    /// Edited since it was loaded or last saved.
+    modified: bool,
+    /// Line and tab width `graphemes` last split, with the result. The
+    /// cursor line is asked for many times between edits.
+    graphemes: RefCell<Option<GraphemeCache>>,
+}
+
+impl Buffer {
+    /// Holds `text` with its line endings normalized to `\n`, remembering
+    /// the style it had.
+    pub fn new(text: &str) -> Self {
+        Buffer {
+            rope: Rope::from_str(&normalize_line_endings(text)),
+            line_ending: LineEnding::detect(text),
+            encoding: FileEncoding::default(),
+            changed_from: None,
+            modified: false,
+            graphemes: RefCell::new(None),
+        }
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn char(&self, char_idx: usize) -> char {
+        self.rope.char(char_idx)
+    }
+
+    pub fn line(&self, line_idx: usize) -> RopeSlice<'_> {
+        self.rope.line(line_idx)
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> RopeSlice<'_> {
+        self.rope.slice(range)
+    }
+
+    /// Iterates lines starting at `line_idx`.
+    pub fn lines_at(&self, line_idx: usize) -> Lines<'_> {
+        self.rope.lines_at(line_idx)
+    }
+
+    /// Extended grapheme clusters of `line_idx`, in order, with tab stops
+    /// every `tab_width` cells.
+    pub fn graphemes(&self, line_idx: usize, tab_width: usize) -> Rc<[Grapheme]> {
+        if let Some((line, width, graphemes)) = &*self.graphemes.borrow() {
+            if (*line, *width) == (line_idx, tab_width) {
+                return graphemes.clone();
+            }
+        }
+        let slice = self.rope.line(line_idx);
+        let line = slice
+            .as_str()
+            .map_or_else(|| Cow::from(slice.to_string()), Cow::from);
+        let tab_width = tab_width.max(1);
+        let mut col = 0;
+        let mut vcol = 0;
+        let graphemes: Rc<[Grapheme]> = line
+            .graphemes(true)
+            .map(|g| {
+                let len = g.chars().count();
+                let width = match g {
+                    "\t" => tab_width - vcol % tab_width,
+                    _ => g.width().max(1),
+                };
+                let grapheme = Grapheme { col, len, width };
+                col += len;
+                vcol += width;
+                grapheme
+            })
+            .collect();
+        *self.graphemes.borrow_mut() = Some((line_idx, tab_width, graphemes.clone()));
+        graphemes
+    }
+
+    /// Char index of the first char of `line_idx`.
+    pub fn line_to_char(&self, line_idx: usize) -> usize {
+        self.rope.line_to_char(line_idx)
+    }
+
+    /// Line containing the char at `char_idx`.
+    pub fn char_to_line(&self, char_idx: usize) -> usize {
+        self.rope.char_to_line(char_idx)
+    }
+
+    pub fn insert(&mut self, char_idx: usize, text: &str) {
+        self.mark_changed(char_idx);
+        self.rope.insert(char_idx, text);
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        self.mark_changed(range.start);
+        self.rope.remove(range);
+    }
+
+    fn mark_changed(&mut self, char_idx: usize) {
+        *self.graphemes.get_mut() = None;
+        self.modified = true;
+        let line = self.rope.char_to_line(char_idx);
+        self.changed_from = Some(self.changed_from.map_or(line, |l| l.min(line)));
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Records that the text now matches the file.
+    pub fn mark_saved(&mut self) {
+        self.modified = false;
+    }
+
+    /// Switches the line ending the file is saved with, which changes the
+    /// file even though the text stays the same.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if line_ending != self.line_ending {
+            self.line_ending = line_ending;
+            self.modified = true;
+        }
+    }
+
+    /// First line changed since the previous call, if any. Lets caches
+    /// keyed by line know how much of themselves is still valid.
+    pub fn take_changed_from(&mut self) -> Option<usize> {
+        self.changed_from.take()
+    }
+
+    /// The text as it goes in a file: its lines ending in `line_ending`,
+    /// encoded in `encoding`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut text = String::with_capacity(self.rope.len_bytes());
+        for chunk in self.rope.chunks() {
+            text.push_str(chunk);
+        }
+        if self.line_ending != LineEnding::Lf {
+            text = text.replace('\n', self.line_ending.as_str());
+        }
+        self.encoding.encode(&text)
+    }
+}
+
+impl Display for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_most_common_line_ending() {
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\rb\r"), LineEnding::Cr);
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\nb\nc\r\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("no line break"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn holds_lf_only() {
+        let buffer = Buffer::new("a\r\nb\rc\n");
+        assert_eq!(buffer.to_string(), "a\nb\nc\n");
+        assert_eq!(buffer.len_lines(), 4);
+    }
+
+    #[test]
+    fn saves_in_the_original_line_ending() {
+        for text in ["a\nb\n", "a\r\nb\r\n", "a\rb\r", "no line break"] {
+            assert_eq!(Buffer::new(text).to_bytes().unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn saves_mixed_line_endings_in_the_most_common_one() {
+        let buffer = Buffer::new("a\r\nb\r\nc\nd\r");
+        assert_eq!(buffer.to_bytes().unwrap(), b"a\r\nb\r\nc\r\nd\r\n");
+    }
+
+    #[test]
+    fn saves_in_a_converted_line_ending() {
+        let mut buffer = Buffer::new("a\r\nb\r\n");
+        assert!(!buffer.is_modified());
+        buffer.set_line_ending(buffer.line_ending.next());
+        assert!(buffer.is_modified());
+        assert_eq!(buffer.to_bytes().unwrap(), b"a\rb\r");
+    }
+
+    #[test]
+    fn saves_line_endings_in_the_file_encoding() {
+        let mut buffer = Buffer::new("a\r\nb");
+        buffer.encoding = FileEncoding::for_label("utf-16le").unwrap();
+        assert_eq!(buffer.to_bytes().unwrap(), b"a\0\r\0\n\0b\0");
+    }
+
+    #[test]
+    fn keeps_form_feeds_on_their_line() {
+        let buffer = Buffer::new("a\x0cb\u{2028}c\n");
+        assert_eq!(buffer.len_lines(), 2);
+    }
+}
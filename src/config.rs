@@ -0,0 +1,155 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::theme::ColorOverrides;
+
+/// Settings read from `config.toml` in the config directory. Every field
+/// is optional in the file, missing ones keep their defaults:
+///
+/// ```toml
+/// font = "/usr/share/fonts/TTF/DejaVuSansMono.ttf"
+/// font_size = 16
+/// fallback_fonts = ["/usr/share/fonts/noto/NotoSansSymbols2-Regular.ttf"]
+/// gap = 8
+/// theme = "solarized-dark"
+/// tab_width = 8
+/// expand_tabs = false
+///
+/// # Still read, colors set here win over the theme's
+/// [colors]
+/// background = "#282828"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub font: String,
+    pub font_size: u16,
+    /// Fonts tried in order for chars `font` has no glyph for. Paths that
+    /// don't exist are skipped, so the list can name fonts from several
+    /// distros.
+    pub fallback_fonts: Vec<String>,
+    /// Pixels between panels.
+    pub gap: usize,
+    /// Name of a built-in theme or of a file in `themes/`.
+    pub theme: String,
+    /// Columns between tab stops.
+    pub tab_width: usize,
+    /// Whether Tab inserts spaces, in files whose indentation doesn't
+    /// already tell.
+    pub expand_tabs: bool,
+    /// Colors from before themes, applied on top of `theme`.
+    pub colors: ColorOverrides,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            font: String::from("/usr/share/fonts/droid/DroidSansMono.ttf"),
+            font_size: 14,
+            fallback_fonts: vec![
+                String::from("/usr/share/fonts/TTF/DejaVuSansMono.ttf"),
+                String::from("/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf"),
+                String::from("/usr/share/fonts/noto/NotoSansSymbols2-Regular.ttf"),
+                String::from("/usr/share/fonts/truetype/noto/NotoSansSymbols2-Regular.ttf"),
+            ],
+            gap: 5,
+            theme: String::from("gruvbox-dark"),
+            tab_width: 4,
+            expand_tabs: true,
+            colors: ColorOverrides::default(),
+        }
+    }
+}
+
+/// Why the config file could not be used. Displays as the path followed
+/// by what went wrong, with the offending line for syntax errors.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownTheme(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::UnknownTheme(name) => write!(f, "no theme named `{}`", name),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config at `path`. A missing file is not an error, it
+    /// just means the defaults.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+        toml::from_str(&source).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/txt`, or `~/.config/txt`.
+pub fn config_dir() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("txt"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Notices when a file is saved by polling its modification time, at most
+/// every `interval` so it is cheap to call every frame.
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    interval: Duration,
+}
+
+impl Watcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified(&path);
+        Watcher {
+            path,
+            modified,
+            last_check: Instant::now(),
+            interval: Duration::from_millis(500),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last time this returned true.
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,226 @@
+use std::fmt::Display;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, REPLACEMENT, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Bytes looked at to tell UTF-16 without a BOM and binary files apart
+/// from text.
+const SNIFF_LEN: usize = 8192;
+
+/// How a file's bytes map to text, kept so it can be written back the
+/// same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEncoding {
+    pub encoding: &'static Encoding,
+    /// The file starts with a byte order mark.
+    pub bom: bool,
+}
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        FileEncoding {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+impl Display for FileEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.encoding.name())?;
+        if self.bom {
+            write!(f, " BOM")?;
+        }
+        Ok(())
+    }
+}
+
+impl FileEncoding {
+    /// The encoding a label such as `latin1` or `utf-16le` names, as the
+    /// WHATWG Encoding Standard maps them.
+    pub fn for_label(label: &str) -> Result<FileEncoding, String> {
+        match Encoding::for_label(label.trim().as_bytes()) {
+            Some(encoding) if encoding != REPLACEMENT => Ok(FileEncoding {
+                encoding,
+                bom: false,
+            }),
+            _ => Err(format!("unknown encoding: {}", label)),
+        }
+    }
+
+    /// Works out what `bytes` are encoded in: a BOM if there is one, then
+    /// UTF-16 by where its zero bytes fall, then UTF-8 if they are valid
+    /// UTF-8, and otherwise the legacy encoding the text looks most like.
+    /// Fails for binary files, which no encoding would round trip.
+    pub fn detect(bytes: &[u8]) -> Result<FileEncoding, String> {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            return Ok(FileEncoding {
+                encoding,
+                bom: true,
+            });
+        }
+        if let Some(encoding) = utf16_without_bom(bytes) {
+            return Ok(FileEncoding {
+                encoding,
+                bom: false,
+            });
+        }
+        if bytes[..bytes.len().min(SNIFF_LEN)].contains(&0) {
+            return Err(String::from("binary file"));
+        }
+        let encoding = if std::str::from_utf8(bytes).is_ok() {
+            UTF_8
+        } else {
+            let mut detector = EncodingDetector::new();
+            detector.feed(bytes, true);
+            detector.guess(None, false)
+        };
+        Ok(FileEncoding {
+            encoding,
+            bom: false,
+        })
+    }
+
+    /// Reads `bytes` as this encoding, skipping its BOM if they start with
+    /// one. Bytes that aren't valid in it become U+FFFD, and the second
+    /// value tells whether there were any, as saving would lose them.
+    pub fn decode(&mut self, bytes: &[u8]) -> (String, bool) {
+        let bom = Encoding::for_bom(bytes).filter(|(encoding, _)| *encoding == self.encoding);
+        self.bom = bom.is_some();
+        let bytes = &bytes[bom.map_or(0, |(_, len)| len)..];
+        let (text, lossy) = self.encoding.decode_without_bom_handling(bytes);
+        (text.into_owned(), lossy)
+    }
+
+    /// `text` in this encoding, with a BOM if the file had one. Fails if
+    /// the encoding has no bytes for some of its chars, rather than
+    /// writing them as HTML character references.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            // encoding_rs only decodes UTF-16
+            let le = self.encoding == UTF_16LE;
+            let units = self.bom.then_some(0xfeff).into_iter();
+            for unit in units.chain(text.encode_utf16()) {
+                bytes.extend(if le {
+                    unit.to_le_bytes()
+                } else {
+                    unit.to_be_bytes()
+                });
+            }
+            return Ok(bytes);
+        }
+        if self.bom && self.encoding == UTF_8 {
+            bytes.extend(b"\xEF\xBB\xBF");
+        }
+        let (encoded, _, unmappable) = self.encoding.encode(text);
+        if unmappable {
+            return Err(format!("text can't be saved as {}", self.encoding.name()));
+        }
+        bytes.extend_from_slice(&encoded);
+        Ok(bytes)
+    }
+}
+
+/// UTF-16 in the byte order whose high bytes are mostly zero, as they are
+/// for Latin text. Binary files have zeros all over and match neither.
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    let bytes = &bytes[..bytes.len().min(SNIFF_LEN) & !1];
+    if bytes.is_empty() {
+        return None;
+    }
+    let pairs = bytes.len() / 2;
+    let even = bytes.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd = bytes.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+    if odd * 2 > pairs && even * 16 < pairs {
+        Some(UTF_16LE)
+    } else if even * 2 > pairs && odd * 16 < pairs {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, le: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|u| if le { u.to_le_bytes() } else { u.to_be_bytes() })
+            .collect()
+    }
+
+    /// Detects, decodes and encodes `bytes` back.
+    fn round_trip(bytes: &[u8]) -> (FileEncoding, String, Vec<u8>) {
+        let mut encoding = FileEncoding::detect(bytes).unwrap();
+        let (text, lossy) = encoding.decode(bytes);
+        assert!(!lossy);
+        let saved = encoding.encode(&text).unwrap();
+        (encoding, text, saved)
+    }
+
+    #[test]
+    fn keeps_a_utf8_bom() {
+        let bytes = b"\xEF\xBB\xBFcaf\xC3\xA9\n";
+        let (encoding, text, saved) = round_trip(bytes);
+        assert_eq!(encoding.encoding, UTF_8);
+        assert!(encoding.bom);
+        assert_eq!(text, "café\n");
+        assert_eq!(saved, bytes);
+    }
+
+    #[test]
+    fn reads_utf16_with_a_bom() {
+        for (bom, le) in [(b"\xFF\xFE", true), (b"\xFE\xFF", false)] {
+            let bytes = [&bom[..], &utf16("hé\n", le)].concat();
+            let (encoding, text, saved) = round_trip(&bytes);
+            assert_eq!(encoding.encoding, if le { UTF_16LE } else { UTF_16BE });
+            assert!(encoding.bom);
+            assert_eq!(text, "hé\n");
+            assert_eq!(saved, bytes);
+        }
+    }
+
+    #[test]
+    fn detects_utf16_without_a_bom() {
+        for le in [true, false] {
+            let bytes = utf16("fn main() {}\n", le);
+            let (encoding, text, saved) = round_trip(&bytes);
+            assert_eq!(encoding.encoding, if le { UTF_16LE } else { UTF_16BE });
+            assert!(!encoding.bom);
+            assert_eq!(text, "fn main() {}\n");
+            assert_eq!(saved, bytes);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_a_legacy_encoding() {
+        let bytes = b"caf\xE9 cr\xE8me br\xFBl\xE9e, na\xEFve fa\xE7ade\n";
+        let (encoding, text, saved) = round_trip(bytes);
+        assert_eq!(encoding.encoding, encoding_rs::WINDOWS_1252);
+        assert_eq!(text, "café crème brûlée, naïve façade\n");
+        assert_eq!(saved, bytes);
+    }
+
+    #[test]
+    fn refuses_binary_files() {
+        let elf = b"\x7FELF\x02\x01\x01\x00\x00\x00\x00\x00\x03\x00\x3E\x00\x01\x00\x00\x00";
+        assert!(FileEncoding::detect(elf).is_err());
+    }
+
+    #[test]
+    fn reports_lossy_decodes() {
+        let mut encoding = FileEncoding::default();
+        let (text, lossy) = encoding.decode(b"caf\xE9");
+        assert_eq!(text, "caf\u{FFFD}");
+        assert!(lossy);
+    }
+
+    #[test]
+    fn refuses_chars_the_encoding_lacks() {
+        let latin1 = FileEncoding::for_label("latin1").unwrap();
+        assert!(latin1.encode("日本").is_err());
+        assert!(FileEncoding::for_label("no-such-encoding").is_err());
+    }
+}
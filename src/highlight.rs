@@ -0,0 +1,749 @@
+use std::ops::Range;
+
+use crate::buffer::Buffer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Keyword,
+    Type,
+    Function,
+    String,
+    Number,
+    Constant,
+    Comment,
+    Preprocessor,
+    Property,
+    Heading,
+    Emphasis,
+    Code,
+    Link,
+    Punctuation,
+}
+
+/// A run of chars in a line, by column, that share a scope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub cols: Range<usize>,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    C,
+    Toml,
+    Markdown,
+}
+
+impl Language {
+    /// Picks a language from the file extension, falling back to a look at
+    /// the first line for extensionless files.
+    pub fn detect(path: &str, first_line: &str) -> Option<Language> {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+        match ext {
+            "rs" => Some(Language::Rust),
+            "c" | "h" => Some(Language::C),
+            "toml" => Some(Language::Toml),
+            "md" | "markdown" => Some(Language::Markdown),
+            _ if first_line.starts_with("#include") => Some(Language::C),
+            _ => None,
+        }
+    }
+
+    fn syntax(&self) -> Syntax {
+        match self {
+            Language::Rust => RUST,
+            Language::C => C,
+            Language::Toml => TOML,
+            Language::Markdown => MARKDOWN,
+        }
+    }
+}
+
+/// What a line inherits from the lines before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Normal,
+    /// Inside a block comment, at the given nesting depth.
+    Comment(usize),
+    /// Inside a string that ends at `close`.
+    String {
+        close: String,
+        escapes: bool,
+    },
+    /// Inside a fenced Markdown code block.
+    Fence,
+}
+
+struct Syntax {
+    line_comment: &'static str,
+    block_comment: Option<(&'static str, &'static str)>,
+    nested_comments: bool,
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    constants: &'static [&'static str],
+}
+
+const RUST: Syntax = Syntax {
+    line_comment: "//",
+    block_comment: Some(("/*", "*/")),
+    nested_comments: true,
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type",
+        "unsafe", "use", "where", "while",
+    ],
+    types: &[
+        "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+        "i64", "i128", "isize", "f32", "f64",
+    ],
+    constants: &["true", "false", "None", "Some", "Ok", "Err"],
+};
+
+const C: Syntax = Syntax {
+    line_comment: "//",
+    block_comment: Some(("/*", "*/")),
+    nested_comments: false,
+    keywords: &[
+        "break", "case", "const", "continue", "default", "do", "else", "enum", "extern", "for",
+        "goto", "if", "inline", "register", "return", "sizeof", "static", "struct", "switch",
+        "typedef", "union", "volatile", "while",
+    ],
+    types: &[
+        "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void", "size_t",
+        "bool",
+    ],
+    constants: &["NULL", "true", "false"],
+};
+
+const TOML: Syntax = Syntax {
+    line_comment: "#",
+    block_comment: None,
+    nested_comments: false,
+    keywords: &[],
+    types: &[],
+    constants: &["true", "false", "inf", "nan"],
+};
+
+const MARKDOWN: Syntax = Syntax {
+    line_comment: "",
+    block_comment: None,
+    nested_comments: false,
+    keywords: &[],
+    types: &[],
+    constants: &[],
+};
+
+/// Per-buffer highlighting.
+///
+/// The lexer state at the start of every line is cached, and a line's
+/// state only depends on the lines above it, so after an edit only the
+/// lines from the first changed one down to the viewport get lexed again.
+pub struct Highlighter {
+    pub language: Option<Language>,
+    /// `states[n]` is the state at the start of line `n`.
+    states: Vec<State>,
+}
+
+impl Highlighter {
+    pub fn new(language: Option<Language>) -> Self {
+        Highlighter {
+            language,
+            states: vec![State::Normal],
+        }
+    }
+
+    /// Forgets everything cached below `line`, which has changed.
+    pub fn invalidate_from(&mut self, line: usize) {
+        self.states.truncate(line + 1);
+    }
+
+    pub fn highlight_line(&mut self, buffer: &Buffer, line: usize) -> Vec<Span> {
+        let Some(language) = self.language else {
+            return Vec::new();
+        };
+        while self.states.len() <= line {
+            let n = self.states.len() - 1;
+            let (_, next) = lex_line(language, &self.states[n], &line_chars(buffer, n));
+            self.states.push(next);
+        }
+
+        let (spans, next) = lex_line(language, &self.states[line], &line_chars(buffer, line));
+        if self.states.len() == line + 1 {
+            self.states.push(next);
+        }
+        spans
+    }
+}
+
+fn line_chars(buffer: &Buffer, line: usize) -> Vec<char> {
+    if line >= buffer.len_lines() {
+        return Vec::new();
+    }
+    buffer.line(line).chars().collect()
+}
+
+fn lex_line(language: Language, state: &State, line: &[char]) -> (Vec<Span>, State) {
+    match language {
+        Language::Markdown => lex_markdown(state, line),
+        _ => Lexer::new(language, line).run(state.clone()),
+    }
+}
+
+fn starts_with(line: &[char], i: usize, pat: &str) -> bool {
+    !pat.is_empty()
+        && pat
+            .chars()
+            .enumerate()
+            .all(|(k, c)| line.get(i + k) == Some(&c))
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Lexer for the C-like languages, one line at a time.
+struct Lexer<'a> {
+    language: Language,
+    syntax: Syntax,
+    line: &'a [char],
+    spans: Vec<Span>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(language: Language, line: &'a [char]) -> Self {
+        Lexer {
+            language,
+            syntax: language.syntax(),
+            line,
+            spans: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, cols: Range<usize>, scope: Scope) {
+        if !cols.is_empty() {
+            self.spans.push(Span { cols, scope });
+        }
+    }
+
+    fn run(mut self, mut state: State) -> (Vec<Span>, State) {
+        let n = self.line.len();
+        let mut i = 0;
+
+        // Finish whatever the previous line left open.
+        match state {
+            State::Comment(depth) => {
+                let (end, depth) = self.block_comment(0, depth);
+                self.push(0..end, Scope::Comment);
+                i = end;
+                state = if depth == 0 {
+                    State::Normal
+                } else {
+                    State::Comment(depth)
+                };
+            }
+            State::String { ref close, escapes } => {
+                let (end, closed) = self.string_end(0, close, escapes);
+                self.push(0..end, Scope::String);
+                i = end;
+                if closed {
+                    state = State::Normal;
+                }
+            }
+            _ => {}
+        }
+        if state != State::Normal {
+            return (self.spans, state);
+        }
+
+        let first = self
+            .line
+            .iter()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or(n);
+        if self.language == Language::C && self.line.get(first) == Some(&'#') {
+            let end = self.comment_start(first).unwrap_or(n);
+            self.push(first..end, Scope::Preprocessor);
+            i = end;
+        }
+        if self.language == Language::Toml && i == 0 && self.line.get(first) == Some(&'[') {
+            let end = self.line[first..]
+                .iter()
+                .rposition(|&c| c == ']')
+                .map_or(n, |p| first + p + 1);
+            self.push(first..end, Scope::Type);
+            i = end;
+        }
+
+        while i < n {
+            let c = self.line[i];
+
+            if starts_with(self.line, i, self.syntax.line_comment) {
+                self.push(i..n, Scope::Comment);
+                break;
+            }
+            if let Some((open, _)) = self.syntax.block_comment {
+                if starts_with(self.line, i, open) {
+                    let (end, depth) = self.block_comment(i + open.len(), 1);
+                    self.push(i..end, Scope::Comment);
+                    if depth > 0 {
+                        return (self.spans, State::Comment(depth));
+                    }
+                    i = end;
+                    continue;
+                }
+            }
+            if let Some((body, close, escapes)) = self.string_start(i) {
+                let (end, closed) = self.string_end(body, &close, escapes);
+                self.push(i..end, Scope::String);
+                if !closed {
+                    return (self.spans, State::String { close, escapes });
+                }
+                i = end;
+                continue;
+            }
+            if self.language == Language::Rust && c == '\'' {
+                i = self.quote(i);
+                continue;
+            }
+            if self.language == Language::Rust
+                && (starts_with(self.line, i, "#[") || starts_with(self.line, i, "#!["))
+            {
+                let end = self.line[i..]
+                    .iter()
+                    .position(|&c| c == ']')
+                    .map_or(n, |p| i + p + 1);
+                self.push(i..end, Scope::Preprocessor);
+                i = end;
+                continue;
+            }
+            if c.is_ascii_digit() {
+                let end = self.number(i);
+                self.push(i..end, Scope::Number);
+                i = end;
+                continue;
+            }
+            if is_ident(c) || (self.language == Language::Toml && c == '-') {
+                let end = self.ident(i);
+                let scope = self.ident_scope(i, end);
+                if let Some(scope) = scope {
+                    let end = if scope == Scope::Function && self.line.get(end) == Some(&'!') {
+                        end + 1
+                    } else {
+                        end
+                    };
+                    self.push(i..end, scope);
+                    i = end;
+                } else {
+                    i = end;
+                }
+                continue;
+            }
+            i += 1;
+        }
+
+        (self.spans, State::Normal)
+    }
+
+    fn comment_start(&self, from: usize) -> Option<usize> {
+        (from..self.line.len()).find(|&i| {
+            starts_with(self.line, i, self.syntax.line_comment)
+                || self
+                    .syntax
+                    .block_comment
+                    .is_some_and(|(open, _)| starts_with(self.line, i, open))
+        })
+    }
+
+    /// Scans a block comment body from `i`. Returns where the scan stopped
+    /// and how many comments are still open there.
+    fn block_comment(&self, mut i: usize, mut depth: usize) -> (usize, usize) {
+        let Some((open, close)) = self.syntax.block_comment else {
+            return (i, 0);
+        };
+        while i < self.line.len() {
+            if starts_with(self.line, i, close) {
+                depth -= 1;
+                i += close.len();
+                if depth == 0 {
+                    return (i, 0);
+                }
+            } else if self.syntax.nested_comments && starts_with(self.line, i, open) {
+                depth += 1;
+                i += open.len();
+            } else {
+                i += 1;
+            }
+        }
+        (i, depth)
+    }
+
+    /// Recognises a string opening at `i`. Returns where its body starts,
+    /// the delimiter that closes it and whether backslash escapes apply.
+    fn string_start(&self, i: usize) -> Option<(usize, String, bool)> {
+        let line = self.line;
+        match self.language {
+            Language::Toml => {
+                for delim in ["\"\"\"", "'''", "\"", "'"] {
+                    if starts_with(line, i, delim) {
+                        let escapes = delim.starts_with('"');
+                        return Some((i + delim.len(), delim.to_string(), escapes));
+                    }
+                }
+                None
+            }
+            Language::Rust => {
+                // b"..", r"..", r#".."#, br#".."#
+                let mut j = i;
+                if line.get(j) == Some(&'b') {
+                    j += 1;
+                }
+                let raw = line.get(j) == Some(&'r');
+                if raw {
+                    j += 1;
+                }
+                let hashes = line[j.min(line.len())..]
+                    .iter()
+                    .take_while(|&&c| c == '#')
+                    .count();
+                if line.get(j + hashes) != Some(&'"') || (!raw && hashes > 0) {
+                    return None;
+                }
+                if j > i && (i > 0 && is_ident(line[i - 1])) {
+                    return None;
+                }
+                let close = format!("\"{}", "#".repeat(hashes));
+                Some((j + hashes + 1, close, !raw))
+            }
+            _ => (line[i] == '"' || line[i] == '\'').then(|| (i + 1, line[i].to_string(), true)),
+        }
+    }
+
+    /// Scans a string body from `i`. Returns where the scan stopped and
+    /// whether the closing delimiter was found.
+    fn string_end(&self, mut i: usize, close: &str, escapes: bool) -> (usize, bool) {
+        while i < self.line.len() {
+            if escapes && self.line[i] == '\\' {
+                i += 2;
+            } else if starts_with(self.line, i, close) {
+                return (i + close.chars().count(), true);
+            } else {
+                i += 1;
+            }
+        }
+        (self.line.len(), false)
+    }
+
+    /// Rust `'`: either a char literal or a lifetime.
+    fn quote(&mut self, i: usize) -> usize {
+        let line = self.line;
+        if line.get(i + 1) == Some(&'\\') {
+            let end = (i + 2..line.len())
+                .find(|&k| line[k] == '\'')
+                .map_or(line.len(), |k| k + 1);
+            self.push(i..end, Scope::String);
+            return end;
+        }
+        if line.get(i + 2) == Some(&'\'') {
+            self.push(i..i + 3, Scope::String);
+            return i + 3;
+        }
+        let end = self.ident(i + 1);
+        self.push(i..end, Scope::Type);
+        end.max(i + 1)
+    }
+
+    fn number(&self, mut i: usize) -> usize {
+        let line = self.line;
+        while i < line.len() {
+            let c = line[i];
+            let continues = is_ident(c)
+                || (c == '.' && line.get(i + 1) != Some(&'.'))
+                || (self.language == Language::Toml && matches!(c, '-' | ':' | '+'));
+            if !continues {
+                break;
+            }
+            i += 1;
+        }
+        i
+    }
+
+    fn ident(&self, mut i: usize) -> usize {
+        while i < self.line.len()
+            && (is_ident(self.line[i]) || (self.language == Language::Toml && self.line[i] == '-'))
+        {
+            i += 1;
+        }
+        i
+    }
+
+    fn ident_scope(&self, start: usize, end: usize) -> Option<Scope> {
+        let word: String = self.line[start..end].iter().collect();
+        let next = self.line[end..].iter().find(|c| !c.is_whitespace());
+
+        if self.syntax.keywords.contains(&word.as_str()) {
+            return Some(Scope::Keyword);
+        }
+        if self.syntax.types.contains(&word.as_str()) {
+            return Some(Scope::Type);
+        }
+        if self.syntax.constants.contains(&word.as_str()) {
+            return Some(Scope::Constant);
+        }
+        match self.language {
+            Language::Toml if matches!(next, Some('=') | Some('.')) => Some(Scope::Property),
+            Language::Rust if self.line.get(end) == Some(&'!') => Some(Scope::Function),
+            Language::Rust if word.starts_with(|c: char| c.is_uppercase()) => Some(Scope::Type),
+            Language::Rust | Language::C if next == Some(&'(') => Some(Scope::Function),
+            _ => None,
+        }
+    }
+}
+
+fn lex_markdown(state: &State, line: &[char]) -> (Vec<Span>, State) {
+    let n = line.len();
+    let text_end = line
+        .iter()
+        .rposition(|c| !c.is_whitespace())
+        .map_or(0, |p| p + 1);
+    let first = line.iter().position(|c| *c != ' ').unwrap_or(n);
+    let whole = vec![Span {
+        cols: first..text_end,
+        scope: Scope::Code,
+    }];
+
+    if starts_with(line, first, "```") || starts_with(line, first, "~~~") {
+        let next = if *state == State::Fence {
+            State::Normal
+        } else {
+            State::Fence
+        };
+        return (whole, next);
+    }
+    if *state == State::Fence {
+        return (whole, State::Fence);
+    }
+
+    let hashes = line[first..].iter().take_while(|&&c| c == '#').count();
+    if (1..=6).contains(&hashes) && line.get(first + hashes).is_none_or(|c| c.is_whitespace()) {
+        let heading = Span {
+            cols: first..text_end,
+            scope: Scope::Heading,
+        };
+        return (vec![heading], State::Normal);
+    }
+
+    let mut spans = Vec::new();
+    let mut i = first;
+
+    // Block quote and list markers.
+    if line.get(i) == Some(&'>') {
+        spans.push(Span {
+            cols: i..i + 1,
+            scope: Scope::Punctuation,
+        });
+        i += 1;
+    }
+    let rest = &line[i..];
+    let marker = rest.iter().position(|c| *c != ' ').unwrap_or(rest.len());
+    let digits = rest[marker..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    let bullet = if matches!(rest.get(marker), Some('-' | '*' | '+')) {
+        1
+    } else if digits > 0 && matches!(rest.get(marker + digits), Some('.' | ')')) {
+        digits + 1
+    } else {
+        0
+    };
+    if bullet > 0 && rest.get(marker + bullet).is_none_or(|c| c.is_whitespace()) {
+        spans.push(Span {
+            cols: i + marker..i + marker + bullet,
+            scope: Scope::Punctuation,
+        });
+        i += marker + bullet;
+    }
+
+    let find = |from: usize, pat: &str| (from..n).find(|&k| starts_with(line, k, pat));
+    while i < n {
+        match line[i] {
+            '`' => {
+                let end = find(i + 1, "`").map_or(n, |k| k + 1);
+                spans.push(Span {
+                    cols: i..end,
+                    scope: Scope::Code,
+                });
+                i = end;
+            }
+            c @ ('*' | '_') => {
+                let delim: String = if line.get(i + 1) == Some(&c) {
+                    [c, c].iter().collect()
+                } else {
+                    c.to_string()
+                };
+                let len = delim.len();
+                match find(i + len, &delim) {
+                    Some(k) if k > i + len => {
+                        spans.push(Span {
+                            cols: i..k + len,
+                            scope: Scope::Emphasis,
+                        });
+                        i = k + len;
+                    }
+                    _ => i += len,
+                }
+            }
+            '[' => {
+                let link_end = find(i + 1, "](").and_then(|k| find(k + 2, ")"));
+                match link_end {
+                    Some(end) => {
+                        spans.push(Span {
+                            cols: i..end + 1,
+                            scope: Scope::Link,
+                        });
+                        i = end + 1;
+                    }
+                    None => i += 1,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    (spans, State::Normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text each span covers in `line`, line break included, with its
+    /// scope.
+    fn scopes(line: &str, spans: &[Span]) -> Vec<(String, Scope)> {
+        let chars: Vec<char> = line.chars().collect();
+        spans
+            .iter()
+            .map(|s| (chars[s.cols.clone()].iter().collect(), s.scope))
+            .collect()
+    }
+
+    fn highlight(language: Language, text: &str) -> Vec<Vec<(String, Scope)>> {
+        let buffer = Buffer::new(text);
+        let mut highlighter = Highlighter::new(Some(language));
+        text.split_inclusive('\n')
+            .enumerate()
+            .map(|(n, line)| scopes(line, &highlighter.highlight_line(&buffer, n)))
+            .collect()
+    }
+
+    fn span(text: &str, scope: Scope) -> (String, Scope) {
+        (text.to_string(), scope)
+    }
+
+    #[test]
+    fn block_comments_carry_across_lines() {
+        let lines = highlight(
+            Language::Rust,
+            "let a = 1; /* one\ntwo /* nested */\n*/ fn\n",
+        );
+        assert_eq!(lines[0].last(), Some(&span("/* one\n", Scope::Comment)));
+        assert_eq!(lines[1], [span("two /* nested */\n", Scope::Comment)]);
+        assert_eq!(
+            lines[2],
+            [span("*/", Scope::Comment), span("fn", Scope::Keyword)]
+        );
+
+        // C comments don't nest, so the first `*/` closes them.
+        let lines = highlight(Language::C, "/* a /* b */ int\n");
+        assert_eq!(
+            lines[0],
+            [
+                span("/* a /* b */", Scope::Comment),
+                span("int", Scope::Type)
+            ]
+        );
+    }
+
+    #[test]
+    fn rust_raw_strings() {
+        let lines = highlight(Language::Rust, "r#\"a \" \\\"# x\nbr\"\\\" fn\n");
+        assert_eq!(lines[0], [span("r#\"a \" \\\"#", Scope::String)]);
+        assert_eq!(
+            lines[1],
+            [span("br\"\\\"", Scope::String), span("fn", Scope::Keyword)]
+        );
+
+        // A raw string left open continues on the next line.
+        let lines = highlight(Language::Rust, "r##\"one\n\"# two\"## fn\n");
+        assert_eq!(
+            lines[1],
+            [
+                span("\"# two\"##", Scope::String),
+                span("fn", Scope::Keyword)
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_tables_and_keys() {
+        let lines = highlight(
+            Language::Toml,
+            "[package]\nname = \"txt\"\n[[bin]]\nopt-level.x = true # yes\n",
+        );
+        assert_eq!(lines[0], [span("[package]", Scope::Type)]);
+        assert_eq!(
+            lines[1],
+            [
+                span("name", Scope::Property),
+                span("\"txt\"", Scope::String)
+            ]
+        );
+        assert_eq!(lines[2], [span("[[bin]]", Scope::Type)]);
+        assert_eq!(
+            lines[3],
+            [
+                span("opt-level", Scope::Property),
+                span("x", Scope::Property),
+                span("true", Scope::Constant),
+                span("# yes\n", Scope::Comment),
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_fences() {
+        let lines = highlight(
+            Language::Markdown,
+            "```rust\n# not a heading\n```\n# heading\n",
+        );
+        assert_eq!(lines[0], [span("```rust", Scope::Code)]);
+        assert_eq!(lines[1], [span("# not a heading", Scope::Code)]);
+        assert_eq!(lines[2], [span("```", Scope::Code)]);
+        assert_eq!(lines[3], [span("# heading", Scope::Heading)]);
+    }
+
+    #[test]
+    fn relexes_after_an_edit_opens_a_comment() {
+        let mut buffer = Buffer::new("fn a() {}\nlet b = 1;\n");
+        let mut highlighter = Highlighter::new(Some(Language::Rust));
+        highlighter.highlight_line(&buffer, 0);
+        let before = highlighter.highlight_line(&buffer, 1);
+        assert_eq!(
+            scopes("let b = 1;\n", &before)[0],
+            span("let", Scope::Keyword)
+        );
+
+        buffer.insert(0, "/* ");
+        highlighter.invalidate_from(0);
+        let after = highlighter.highlight_line(&buffer, 1);
+        assert_eq!(
+            scopes("let b = 1;\n", &after),
+            [span("let b = 1;\n", Scope::Comment)]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{buffer::Buffer, text::Cursor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditKind {
+    Insert,
+    Delete,
+}
+
+/// A single change to a buffer, recorded with enough information to be
+/// reverted. `idx` is the char index where `text` was inserted or removed.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub kind: EditKind,
+    pub idx: usize,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn apply(&self, buffer: &mut Buffer) {
+        match self.kind {
+            EditKind::Insert => buffer.insert(self.idx, &self.text),
+            EditKind::Delete => buffer.remove(self.idx..self.idx + self.len()),
+        }
+    }
+
+    pub fn revert(&self, buffer: &mut Buffer) {
+        match self.kind {
+            EditKind::Insert => buffer.remove(self.idx..self.idx + self.len()),
+            EditKind::Delete => buffer.insert(self.idx, &self.text),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+}
+
+/// One undo step: the edits it is made of plus where the cursors were
+/// before and after them. Edits are applied in order and reverted in
+/// reverse order.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub edits: Vec<Edit>,
+    pub cursors_before: Vec<Cursor>,
+    pub cursors_after: Vec<Cursor>,
+}
+
+impl Transaction {
+    /// Whether `edits`, made right after this transaction with the cursors
+    /// left where it put them, continue the same run of typing or deleting.
+    /// Newlines always start a new step.
+    fn continues_with(&self, edits: &[Edit], cursors_before: &[Cursor]) -> bool {
+        let Some(kind) = self.edits.last().map(|e| e.kind) else {
+            return false;
+        };
+        self.cursors_after == cursors_before
+            && !self.edits.iter().any(|e| e.text.ends_with('\n'))
+            && edits
+                .iter()
+                .all(|e| e.kind == kind && !e.text.contains('\n'))
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Transaction>,
+    redo: Vec<Transaction>,
+    pending: Vec<Edit>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    /// Records an edit that has already been applied to the buffer. It
+    /// becomes undoable once the surrounding operation calls `commit`.
+    pub fn record(&mut self, edit: Edit) {
+        self.pending.push(edit);
+    }
+
+    /// Closes the edits recorded since the last commit into one undo step,
+    /// folding them into the previous step when they continue it.
+    /// Committing anything new drops the redo stack.
+    pub fn commit(&mut self, cursors_before: Vec<Cursor>, cursors_after: Vec<Cursor>) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let edits = std::mem::take(&mut self.pending);
+        self.redo.clear();
+
+        if let Some(last) = self.undo.last_mut() {
+            if last.continues_with(&edits, &cursors_before) {
+                last.edits.extend(edits);
+                last.cursors_after = cursors_after;
+                return;
+            }
+        }
+
+        self.undo.push(Transaction {
+            edits,
+            cursors_before,
+            cursors_after,
+        });
+    }
+
+    /// Pops the latest transaction so the caller can revert it.
+    pub fn undo(&mut self) -> Option<&Transaction> {
+        let tx = self.undo.pop()?;
+        self.redo.push(tx);
+        self.redo.last()
+    }
+
+    /// Pops the latest undone transaction so the caller can re-apply it.
+    pub fn redo(&mut self) -> Option<&Transaction> {
+        let tx = self.redo.pop()?;
+        self.undo.push(tx);
+        self.undo.last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::CursorPosition;
+
+    fn cursor(col: usize) -> Vec<Cursor> {
+        vec![Cursor::new(CursorPosition { line: 0, col })]
+    }
+
+    /// Types `text` at `col` of `buffer` and commits it as one edit.
+    fn type_at(history: &mut History, buffer: &mut Buffer, col: usize, text: &str) {
+        let edit = Edit {
+            kind: EditKind::Insert,
+            idx: col,
+            text: String::from(text),
+        };
+        edit.apply(buffer);
+        history.record(edit);
+        history.commit(cursor(col), cursor(col + text.chars().count()));
+    }
+
+    fn undo(history: &mut History, buffer: &mut Buffer) -> Vec<Cursor> {
+        let tx = history.undo().unwrap();
+        for edit in tx.edits.iter().rev() {
+            edit.revert(buffer);
+        }
+        tx.cursors_before.clone()
+    }
+
+    #[test]
+    fn groups_a_run_of_typing() {
+        let mut buffer = Buffer::new("");
+        let mut history = History::new();
+        for (col, c) in "abc".chars().enumerate() {
+            type_at(&mut history, &mut buffer, col, &c.to_string());
+        }
+        assert_eq!(undo(&mut history, &mut buffer), cursor(0));
+        assert_eq!(buffer.to_string(), "");
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn breaks_groups_when_the_cursor_moves() {
+        let mut buffer = Buffer::new("");
+        let mut history = History::new();
+        type_at(&mut history, &mut buffer, 0, "a");
+        type_at(&mut history, &mut buffer, 1, "b");
+        type_at(&mut history, &mut buffer, 0, "c");
+        undo(&mut history, &mut buffer);
+        assert_eq!(buffer.to_string(), "ab");
+        undo(&mut history, &mut buffer);
+        assert_eq!(buffer.to_string(), "");
+    }
+
+    #[test]
+    fn breaks_groups_at_newlines_and_kind_changes() {
+        let mut buffer = Buffer::new("");
+        let mut history = History::new();
+        type_at(&mut history, &mut buffer, 0, "a");
+        type_at(&mut history, &mut buffer, 1, "\n");
+        type_at(&mut history, &mut buffer, 2, "b");
+
+        let delete = Edit {
+            kind: EditKind::Delete,
+            idx: 2,
+            text: String::from("b"),
+        };
+        delete.apply(&mut buffer);
+        history.record(delete);
+        history.commit(cursor(3), cursor(2));
+
+        undo(&mut history, &mut buffer);
+        assert_eq!(buffer.to_string(), "a\nb");
+        undo(&mut history, &mut buffer);
+        assert_eq!(buffer.to_string(), "a\n");
+        undo(&mut history, &mut buffer);
+        assert_eq!(buffer.to_string(), "a");
+    }
+
+    #[test]
+    fn redoes_until_something_new_is_committed() {
+        let mut buffer = Buffer::new("");
+        let mut history = History::new();
+        type_at(&mut history, &mut buffer, 0, "a");
+        undo(&mut history, &mut buffer);
+
+        let tx = history.redo().unwrap();
+        for edit in &tx.edits {
+            edit.apply(&mut buffer);
+        }
+        assert_eq!(tx.cursors_after, cursor(1));
+        assert_eq!(buffer.to_string(), "a");
+
+        undo(&mut history, &mut buffer);
+        type_at(&mut history, &mut buffer, 0, "b");
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn ignores_commits_without_edits() {
+        let mut history = History::new();
+        history.commit(cursor(0), cursor(1));
+        assert!(history.undo().is_none());
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use sdl2::{
+    event::Event,
+    keyboard::{Keycode, Mod},
+};
+
+use crate::config::config_dir;
+
+/// Everything a key binding can do. Panels get these instead of raw key
+/// events, so they never see which keys were actually pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Quit,
+    ToggleDebug,
+    FontBigger,
+    FontSmaller,
+    Save,
+    Find,
+    FindPrevious,
+    NextField,
+    ToggleRegex,
+    Replace,
+    ReplaceAll,
+    ToggleRelativeNumbers,
+    ToggleWrap,
+    ToggleVim,
+    ToggleExpandTabs,
+    ConvertLineEndings,
+    SaveWithEncoding,
+    ReopenWithEncoding,
+    NextTheme,
+    Undo,
+    Redo,
+    Copy,
+    Cut,
+    Paste,
+    Newline,
+    Tab,
+    Backspace,
+    Escape,
+    AddCursorAbove,
+    AddCursorBelow,
+    Left,
+    Right,
+    Up,
+    Down,
+    LineStart,
+    SelectLeft,
+    SelectRight,
+    SelectUp,
+    SelectDown,
+    SelectLineStart,
+}
+
+/// Names commands go by in the keymap file.
+const COMMANDS: &[(&str, Command)] = &[
+    ("quit", Command::Quit),
+    ("toggle_debug", Command::ToggleDebug),
+    ("font_bigger", Command::FontBigger),
+    ("font_smaller", Command::FontSmaller),
+    ("save", Command::Save),
+    ("find", Command::Find),
+    ("find_previous", Command::FindPrevious),
+    ("next_field", Command::NextField),
+    ("toggle_regex", Command::ToggleRegex),
+    ("replace", Command::Replace),
+    ("replace_all", Command::ReplaceAll),
+    ("toggle_relative_numbers", Command::ToggleRelativeNumbers),
+    ("toggle_wrap", Command::ToggleWrap),
+    ("toggle_vim", Command::ToggleVim),
+    ("toggle_expand_tabs", Command::ToggleExpandTabs),
+    ("convert_line_endings", Command::ConvertLineEndings),
+    ("save_with_encoding", Command::SaveWithEncoding),
+    ("reopen_with_encoding", Command::ReopenWithEncoding),
+    ("next_theme", Command::NextTheme),
+    ("undo", Command::Undo),
+    ("redo", Command::Redo),
+    ("copy", Command::Copy),
+    ("cut", Command::Cut),
+    ("paste", Command::Paste),
+    ("newline", Command::Newline),
+    ("tab", Command::Tab),
+    ("backspace", Command::Backspace),
+    ("escape", Command::Escape),
+    ("add_cursor_above", Command::AddCursorAbove),
+    ("add_cursor_below", Command::AddCursorBelow),
+    ("left", Command::Left),
+    ("right", Command::Right),
+    ("up", Command::Up),
+    ("down", Command::Down),
+    ("line_start", Command::LineStart),
+    ("select_left", Command::SelectLeft),
+    ("select_right", Command::SelectRight),
+    ("select_up", Command::SelectUp),
+    ("select_down", Command::SelectDown),
+    ("select_line_start", Command::SelectLineStart),
+];
+
+const DEFAULT_BINDINGS: &[(&str, &str)] = &[
+    ("ctrl+q", "quit"),
+    ("f1", "toggle_debug"),
+    ("f2", "font_bigger"),
+    ("f3", "font_smaller"),
+    ("ctrl+s", "save"),
+    ("ctrl+f", "find"),
+    ("shift+return", "find_previous"),
+    ("tab", "tab"),
+    ("ctrl+r", "toggle_regex"),
+    ("ctrl+h", "replace"),
+    ("ctrl+shift+h", "replace_all"),
+    ("ctrl+l", "toggle_relative_numbers"),
+    ("ctrl+shift+w", "toggle_wrap"),
+    ("ctrl+k ctrl+v", "toggle_vim"),
+    ("ctrl+k ctrl+t", "next_theme"),
+    ("ctrl+k ctrl+i", "toggle_expand_tabs"),
+    ("ctrl+k ctrl+e", "convert_line_endings"),
+    ("ctrl+k ctrl+n", "save_with_encoding"),
+    ("ctrl+k ctrl+o", "reopen_with_encoding"),
+    ("ctrl+z", "undo"),
+    ("ctrl+shift+z", "redo"),
+    ("ctrl+c", "copy"),
+    ("ctrl+x", "cut"),
+    ("ctrl+v", "paste"),
+    ("return", "newline"),
+    ("backspace", "backspace"),
+    ("escape", "escape"),
+    ("ctrl+alt+up", "add_cursor_above"),
+    ("ctrl+alt+down", "add_cursor_below"),
+    ("left", "left"),
+    ("right", "right"),
+    ("up", "up"),
+    ("down", "down"),
+    ("ctrl+left", "line_start"),
+    ("shift+left", "select_left"),
+    ("shift+right", "select_right"),
+    ("shift+up", "select_up"),
+    ("shift+down", "select_down"),
+    ("ctrl+shift+left", "select_line_start"),
+];
+
+impl Command {
+    pub fn from_name(name: &str) -> Option<Command> {
+        COMMANDS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, command)| *command)
+    }
+}
+
+/// A key plus the modifiers held with it. Left and right modifiers count
+/// as the same, and lock keys such as NumLock and CapsLock are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub key: Keycode,
+    pub mods: Mod,
+}
+
+impl Chord {
+    pub fn new(key: Keycode, mods: Mod) -> Self {
+        let mut normalized = Mod::NOMOD;
+        for (either, left) in [
+            (Mod::LCTRLMOD | Mod::RCTRLMOD, Mod::LCTRLMOD),
+            (Mod::LSHIFTMOD | Mod::RSHIFTMOD, Mod::LSHIFTMOD),
+            (Mod::LALTMOD | Mod::RALTMOD, Mod::LALTMOD),
+            (Mod::LGUIMOD | Mod::RGUIMOD, Mod::LGUIMOD),
+        ] {
+            if mods.intersects(either) {
+                normalized |= left;
+            }
+        }
+        Chord {
+            key,
+            mods: normalized,
+        }
+    }
+
+    /// The chord pressed in a `KeyDown` event. Modifier keys on their own
+    /// are not chords, they only matter held down with another key.
+    pub fn from_event(event: &Event) -> Option<Chord> {
+        let Event::KeyDown {
+            keycode: Some(key),
+            keymod,
+            ..
+        } = event
+        else {
+            return None;
+        };
+        match key {
+            Keycode::LCtrl
+            | Keycode::RCtrl
+            | Keycode::LShift
+            | Keycode::RShift
+            | Keycode::LAlt
+            | Keycode::RAlt
+            | Keycode::LGui
+            | Keycode::RGui => None,
+            _ => Some(Chord::new(*key, *keymod)),
+        }
+    }
+
+    /// Parses chords such as `ctrl+shift+z`, `f1` or `alt+left`. Key names
+    /// are SDL's, in any case.
+    pub fn parse(s: &str) -> Result<Chord, String> {
+        let mut mods = Mod::NOMOD;
+        let mut parts: Vec<&str> = s.split('+').collect();
+        let key = parts.pop().unwrap_or_default();
+        for part in parts {
+            mods |= match part.to_lowercase().as_str() {
+                "ctrl" | "control" => Mod::LCTRLMOD,
+                "shift" => Mod::LSHIFTMOD,
+                "alt" => Mod::LALTMOD,
+                "super" | "gui" | "cmd" => Mod::LGUIMOD,
+                _ => return Err(format!("unknown modifier `{}` in `{}`", part, s)),
+            };
+        }
+        let key = Keycode::from_name(key).ok_or(format!("unknown key `{}` in `{}`", key, s))?;
+        Ok(Chord::new(key, mods))
+    }
+}
+
+pub enum Lookup {
+    Command(Command),
+    /// The keys so far start a longer sequence.
+    Pending,
+    Unbound,
+}
+
+/// Maps sequences of chords to commands, such as `ctrl+s` to save or
+/// `ctrl+k ctrl+v` to toggle Vim mode.
+pub struct Keymap {
+    bindings: HashMap<Vec<Chord>, Command>,
+    pending: Vec<Chord>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::new()
+    }
+}
+
+impl Keymap {
+    /// The built-in bindings.
+    pub fn new() -> Self {
+        let mut keymap = Keymap {
+            bindings: HashMap::new(),
+            pending: Vec::new(),
+        };
+        for (keys, command) in DEFAULT_BINDINGS {
+            keymap.bind(keys, command).unwrap();
+        }
+        keymap
+    }
+
+    /// The built-in bindings overridden by the user's keymap file, which
+    /// maps key sequences to command names:
+    ///
+    /// ```toml
+    /// "ctrl+w" = "toggle_wrap"
+    /// "ctrl+k ctrl+s" = "save"
+    /// "ctrl+l" = ""  # unbinds
+    /// ```
+    ///
+    /// Bad entries are added to `errors` and skipped, the rest still
+    /// apply.
+    pub fn load(errors: &mut Vec<String>) -> Self {
+        let mut keymap = Keymap::new();
+        let Some(path) = config_dir().map(|dir| dir.join("keymap.toml")) else {
+            return keymap;
+        };
+        let Ok(source) = std::fs::read_to_string(&path) else {
+            return keymap;
+        };
+        let table = match source.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => {
+                errors.push(format!("{}: {}", path.display(), e));
+                return keymap;
+            }
+        };
+        for (keys, command) in table {
+            let result = match command.as_str() {
+                Some(command) => keymap.bind(&keys, command),
+                None => Err(format!("command for `{}` must be a string", keys)),
+            };
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+        keymap
+    }
+
+    /// Binds the space separated chords in `keys` to the command named
+    /// `command`, or unbinds them when `command` is empty.
+    pub fn bind(&mut self, keys: &str, command: &str) -> Result<(), String> {
+        let chords = keys
+            .split_whitespace()
+            .map(Chord::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if chords.is_empty() {
+            return Err(String::from("empty key sequence"));
+        }
+        if command.is_empty() {
+            self.bindings.remove(&chords);
+            return Ok(());
+        }
+        let command =
+            Command::from_name(command).ok_or(format!("unknown command `{}`", command))?;
+        self.bindings.insert(chords, command);
+        Ok(())
+    }
+
+    /// Adds `chord` to the keys pressed so far. A finished sequence, or
+    /// one that can no longer match anything, starts over, and the key
+    /// that broke it counts on its own. Modified keys nothing binds act as
+    /// the bare key, so Ctrl+Return still breaks the line.
+    pub fn feed(&mut self, chord: Chord) -> Lookup {
+        self.pending.push(chord);
+        if let Some(command) = self.bindings.get(&self.pending) {
+            self.pending.clear();
+            return Lookup::Command(*command);
+        }
+        if self
+            .bindings
+            .keys()
+            .any(|keys| keys.starts_with(&self.pending))
+        {
+            return Lookup::Pending;
+        }
+        let broken = self.pending.len() > 1;
+        self.pending.clear();
+        if broken {
+            return self.feed(chord);
+        }
+        let bare = [Chord {
+            key: chord.key,
+            mods: Mod::NOMOD,
+        }];
+        match self.bindings.get(&bare[..]) {
+            Some(command) if chord.mods != Mod::NOMOD => Lookup::Command(*command),
+            _ => Lookup::Unbound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctrl(key: Keycode) -> Chord {
+        Chord::new(key, Mod::LCTRLMOD)
+    }
+
+    fn command(lookup: Lookup) -> Option<Command> {
+        match lookup {
+            Lookup::Command(command) => Some(command),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sequences() {
+        let mut keymap = Keymap::new();
+        assert!(matches!(keymap.feed(ctrl(Keycode::K)), Lookup::Pending));
+        // Right Ctrl counts as Ctrl
+        let right = Chord::new(Keycode::V, Mod::RCTRLMOD);
+        assert_eq!(command(keymap.feed(right)), Some(Command::ToggleVim));
+        // The sequence is over, so Ctrl+V pastes again
+        assert_eq!(command(keymap.feed(ctrl(Keycode::V))), Some(Command::Paste));
+    }
+
+    #[test]
+    fn a_broken_sequence_refeeds_the_key() {
+        let mut keymap = Keymap::new();
+        assert!(matches!(keymap.feed(ctrl(Keycode::K)), Lookup::Pending));
+        assert_eq!(command(keymap.feed(ctrl(Keycode::S))), Some(Command::Save));
+        assert!(matches!(keymap.feed(ctrl(Keycode::K)), Lookup::Pending));
+        let a = Chord::new(Keycode::A, Mod::NOMOD);
+        assert!(matches!(keymap.feed(a), Lookup::Unbound));
+        assert!(matches!(keymap.feed(ctrl(Keycode::K)), Lookup::Pending));
+    }
+
+    #[test]
+    fn modified_keys_fall_back_to_the_bare_key() {
+        let mut keymap = Keymap::new();
+        let enter = ctrl(Keycode::Return);
+        assert_eq!(command(keymap.feed(enter)), Some(Command::Newline));
+        // Bound with the modifier, the binding wins
+        let shift_enter = Chord::new(Keycode::Return, Mod::LSHIFTMOD);
+        assert_eq!(
+            command(keymap.feed(shift_enter)),
+            Some(Command::FindPrevious)
+        );
+        keymap.bind("return", "").unwrap();
+        assert!(matches!(keymap.feed(enter), Lookup::Unbound));
+    }
+}
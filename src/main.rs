@@ -1,22 +1,46 @@
 extern crate sdl2;
 
 pub mod atlas;
+pub mod buffer;
+pub mod config;
+pub mod encoding;
+pub mod highlight;
+pub mod history;
+pub mod keymap;
 pub mod layout;
 pub mod panels;
+pub mod search;
+pub mod shape;
 pub mod text;
+pub mod theme;
+pub mod vim;
 
-use atlas::FontAtlas2;
+use atlas::FontAtlas;
+use config::{Config, Watcher};
+use keymap::{Chord, Command, Keymap, Lookup};
 use layout::{Container, ContainerType, Layout};
 use panels::Panel;
 use sdl2::pixels::PixelFormatEnum;
+use shape::Face;
 
+use sdl2::event::Event;
 use sdl2::rect::Point;
 use sdl2::{
-    keyboard::Keycode, pixels::Color, rect::Rect, render::Canvas, ttf::Font, video::Window,
+    pixels::Color,
+    rect::Rect,
+    render::{Canvas, Texture},
+    ttf::{Font, Sdl2TtfContext},
+    video::Window,
 };
 use std::collections::HashMap;
-use std::time::Instant;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use text::TextArea;
+use theme::Theme;
+
+/// Longest the loop sleeps with no events, so the config file is still
+/// polled while idle.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(500);
 
 fn draw_fps(canvas: &mut Canvas<Window>, font: &Font, fps: u32) {
     let x = canvas.viewport().width() - 200;
@@ -40,6 +64,55 @@ fn draw_fps(canvas: &mut Canvas<Window>, font: &Font, fps: u32) {
         .unwrap();
 }
 
+/// Loads the configured font, falling back to the default one when it
+/// can't be opened.
+fn load_font<'ttf>(
+    ttf: &'ttf Sdl2TtfContext,
+    config: &Config,
+    size: u16,
+    errors: &mut Vec<String>,
+) -> Font<'ttf, 'static> {
+    ttf.load_font(&config.font, size).unwrap_or_else(|e| {
+        errors.push(format!("{}: {}", config.font, e));
+        ttf.load_font(Config::default().font, size).unwrap()
+    })
+}
+
+/// Loads the configured theme with the config's color overrides, falling
+/// back to the default theme.
+fn load_theme(config: &Config, errors: &mut Vec<String>) -> Theme {
+    let mut theme = Theme::load(&config.theme).unwrap_or_else(|e| {
+        errors.push(e.to_string());
+        Theme::default()
+    });
+    config.colors.apply(&mut theme);
+    theme
+}
+
+/// Loads the configured font for shaping, falling back to the default
+/// one like `load_font`, followed by the fallback fonts that exist.
+fn load_faces(config: &Config, errors: &mut Vec<String>) -> Vec<Face> {
+    let primary = Face::load(&config.font).unwrap_or_else(|e| {
+        errors.push(e);
+        Face::load(&Config::default().font).unwrap()
+    });
+    let fallbacks = config
+        .fallback_fonts
+        .iter()
+        .filter(|path| Path::new(path).exists())
+        .filter_map(|path| Face::load(path).map_err(|e| errors.push(e)).ok());
+    std::iter::once(primary).chain(fallbacks).collect()
+}
+
+/// Shows `message` in the focused panel's info line, as a GUI app has
+/// nowhere else to put it, and on stderr too.
+fn report(components: &mut HashMap<String, Box<dyn Panel>>, message: String) {
+    eprintln!("{}", message);
+    if let Some(comp) = components.values_mut().find(|comp| comp.is_focused()) {
+        comp.set_message(message);
+    }
+}
+
 fn main() {
     let sdl = sdl2::init().unwrap();
     let video = sdl.video().unwrap();
@@ -47,12 +120,26 @@ fn main() {
     let mut window = video.window("txt", 1024, 768).resizable().build().unwrap();
     window.show();
 
-    let mut font_size = 14;
-    let mut font = ttf
-        .load_font("/usr/share/fonts/droid/DroidSansMono.ttf", font_size)
-        .unwrap();
+    // Problems with the config and what it names, shown once there are
+    // panels to show them in
+    let mut errors = Vec::new();
+    let config_path = config::config_path();
+    let mut config = match config_path.as_deref().map(Config::load) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            errors.push(e.to_string());
+            Config::default()
+        }
+        None => Config::default(),
+    };
+    let mut config_watcher = config_path.map(Watcher::new);
+    let mut theme = load_theme(&config, &mut errors);
+
+    let mut font_size = config.font_size;
+    let mut font = load_font(&ttf, &config, font_size, &mut errors);
     let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl.event_pump().unwrap();
+    let mut keymap = Keymap::load(&mut errors);
     let mut running = true;
     let mut draw_debug_info = true;
 
@@ -60,14 +147,23 @@ fn main() {
 
     let tc2 = canvas.texture_creator();
     let mut cur_time = Instant::now();
-    let mut atlas2 = FontAtlas2::new(&tc2);
-    let text_area = TextArea::new(String::from("/home/jqcorreia/code/tests/txt/SANDBOX"));
-    let t2 = TextArea::new(String::from("up"));
-    let t3 = TextArea::new(String::from("down"));
+    let mut atlas2 = FontAtlas::new(&tc2);
+    atlas2.set_faces(load_faces(&config, &mut errors));
+    atlas2.set_size(font_size);
+    let text_area = TextArea::new(
+        String::from("/home/jqcorreia/code/tests/txt/SANDBOX"),
+        &video,
+    );
+    let t2 = TextArea::new(String::from("up"), &video);
+    let t3 = TextArea::new(String::from("down"), &video);
 
     let mut components: HashMap<String, Box<dyn Panel>> = HashMap::new();
-    let l = Layout {
-        gap: 5,
+    // What each panel drew last, copied again as is while it is clean
+    let mut textures: HashMap<String, Texture> = HashMap::new();
+    // The window needs composing even if no panel changed
+    let mut redraw = true;
+    let mut l = Layout {
+        gap: config.gap,
         root: Container {
             size: 100,
             size_type: layout::SizeTypeEnum::Percent,
@@ -122,44 +218,106 @@ fn main() {
     components.insert(String::from("t2"), Box::new(t2));
     components.insert(String::from("t3"), Box::new(t3));
     components.get_mut("t1").unwrap().focus();
+    for comp in components.values_mut() {
+        comp.set_theme(&theme);
+        comp.set_config(&config);
+    }
 
     while running {
+        // Saving the config file applies it without a restart
+        if let Some(watcher) = config_watcher.as_mut() {
+            if watcher.changed() {
+                match Config::load(watcher.path()) {
+                    Ok(new) => {
+                        config = new;
+                        font_size = config.font_size;
+                        font = load_font(&ttf, &config, font_size, &mut errors);
+                        atlas2.set_faces(load_faces(&config, &mut errors));
+                        atlas2.set_size(font_size);
+                        l.gap = config.gap;
+                        theme = load_theme(&config, &mut errors);
+                        for comp in components.values_mut() {
+                            comp.set_theme(&theme);
+                            comp.set_config(&config);
+                            comp.mark_dirty();
+                        }
+                        redraw = true;
+                    }
+                    Err(e) => errors.push(e.to_string()),
+                }
+            }
+        }
+
         let mut lay = l.generate(
             canvas.window().size().0 as usize,
             canvas.window().size().1 as usize,
         );
 
         n = n + 1;
-        for event in event_pump.poll_iter() {
-            match event {
-                sdl2::event::Event::KeyDown {
-                    keycode: Some(Keycode::F1),
-                    ..
-                } => draw_debug_info = !draw_debug_info,
-                sdl2::event::Event::KeyDown {
-                    keycode: Some(Keycode::F2),
-                    ..
-                } => {
-                    font_size += 1;
-                    font = ttf
-                        .load_font("/usr/share/fonts/droid/DroidSansMono.ttf", font_size)
-                        .unwrap()
-                }
-                sdl2::event::Event::KeyDown {
-                    keycode: Some(Keycode::F3),
-                    ..
-                } => {
-                    font_size -= 1;
-                    font = ttf
-                        .load_font("/usr/share/fonts/droid/DroidSansMono.ttf", font_size)
-                        .unwrap()
+        // Sleep until there is input or a panel changes on its own, unless
+        // there is already something to draw
+        let timeout = components
+            .values_mut()
+            .filter_map(|comp| comp.tick())
+            .fold(IDLE_TIMEOUT, Duration::min);
+        let first = if redraw || components.values().any(|comp| comp.is_dirty()) {
+            event_pump.poll_event()
+        } else {
+            event_pump.wait_event_timeout(timeout.as_millis() as u32)
+        };
+        let events: Vec<Event> = first.into_iter().chain(event_pump.poll_iter()).collect();
+        for event in events {
+            if let Some(chord) = Chord::from_event(&event) {
+                match keymap.feed(chord) {
+                    Lookup::Command(Command::ToggleDebug) => {
+                        draw_debug_info = !draw_debug_info;
+                        redraw = true;
+                    }
+                    Lookup::Command(Command::FontBigger) => {
+                        font_size += 1;
+                        font = load_font(&ttf, &config, font_size, &mut errors);
+                        atlas2.set_size(font_size);
+                        for comp in components.values_mut() {
+                            comp.mark_dirty();
+                        }
+                    }
+                    Lookup::Command(Command::FontSmaller) => {
+                        font_size -= 1;
+                        font = load_font(&ttf, &config, font_size, &mut errors);
+                        atlas2.set_size(font_size);
+                        for comp in components.values_mut() {
+                            comp.mark_dirty();
+                        }
+                    }
+                    Lookup::Command(Command::Quit) => running = false,
+                    Lookup::Command(Command::NextTheme) => match theme.next() {
+                        Ok(mut next) => {
+                            config.colors.apply(&mut next);
+                            theme = next;
+                            for comp in components.values_mut() {
+                                comp.set_theme(&theme);
+                            }
+                            redraw = true;
+                        }
+                        Err(e) => errors.push(e.to_string()),
+                    },
+                    Lookup::Command(command) => {
+                        for (_, key) in lay.iter() {
+                            components.get_mut(key).unwrap().run_command(command);
+                        }
+                    }
+                    Lookup::Pending | Lookup::Unbound => (),
                 }
-                sdl2::event::Event::KeyDown {
-                    keycode: Some(Keycode::Q),
-                    keymod: sdl2::keyboard::Mod::LCTRLMOD,
-                    ..
-                } => running = false,
+            }
+            match event {
                 sdl2::event::Event::Quit { .. } => running = false,
+                // Panel rects come from the window size, resized panels
+                // get new textures below
+                sdl2::event::Event::Window { .. } => redraw = true,
+                sdl2::event::Event::RenderTargetsReset { .. } => {
+                    textures.clear();
+                    redraw = true;
+                }
                 sdl2::event::Event::MouseButtonDown { x, y, .. } => {
                     for (rect, key) in lay.iter() {
                         let comp = components.get_mut(key).unwrap();
@@ -182,25 +340,53 @@ fn main() {
                 comp.consume_event(&event);
             }
         }
+        // All at once, as each message replaces the one before it
+        if !errors.is_empty() {
+            let message = std::mem::take(&mut errors).join("; ");
+            report(&mut components, message);
+        }
+        if !running || !redraw && !components.values().any(|comp| comp.is_dirty()) {
+            continue;
+        }
+        redraw = false;
+        // The window may have been resized while handling events
+        lay = l.generate(
+            canvas.window().size().0 as usize,
+            canvas.window().size().1 as usize,
+        );
+
         let fps = (1_000_000_000 / (&cur_time.elapsed().as_nanos())) as u32;
         cur_time = Instant::now();
 
-        canvas.set_draw_color(Color::RGBA(0, 0, 0, 0));
+        canvas.set_draw_color(theme.window);
         canvas.clear();
 
         for (rect, key) in lay.iter_mut() {
             let comp = components.get_mut(key).unwrap();
-            let mut tex = tc2
-                .create_texture_target(PixelFormatEnum::RGBA8888, rect.width(), rect.height())
-                .unwrap();
+            let resized = !textures.get(key.as_str()).is_some_and(|tex| {
+                let q = tex.query();
+                (q.width, q.height) == (rect.width(), rect.height())
+            });
+            if resized {
+                let tex = tc2
+                    .create_texture_target(PixelFormatEnum::RGBA8888, rect.width(), rect.height())
+                    .unwrap();
+                textures.insert(key.clone(), tex);
+                comp.mark_dirty();
+            }
+            let tex = textures.get_mut(key.as_str()).unwrap();
+            if !comp.is_dirty() {
+                canvas.copy(tex, None, *rect).unwrap();
+                continue;
+            }
 
             canvas
-                .with_texture_canvas(&mut tex, |c| {
+                .with_texture_canvas(tex, |c| {
                     comp.render(&mut atlas2, &font, c, *rect);
                     let border_color = if comp.is_focused() {
-                        Color::RGBA(0, 255, 0, 255)
+                        theme.border_focused
                     } else {
-                        Color::RGBA(100, 100, 100, 255)
+                        theme.border
                     };
                     c.set_draw_color(border_color);
                     c.draw_rect(Rect::new(0, 0, rect.width(), rect.height()))
@@ -208,7 +394,7 @@ fn main() {
                 })
                 .unwrap();
 
-            canvas.copy(&tex, None, *rect).unwrap();
+            canvas.copy(tex, None, *rect).unwrap();
         }
 
         // Draw the FPS counter directly into the window canvas
@@ -1,10 +1,37 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use sdl2::{event::Event, rect::Rect, render::Canvas, ttf::Font, video::Window};
 
-use crate::atlas::FontAtlas2;
+use crate::{atlas::FontAtlas, config::Config, keymap::Command, theme::Theme};
 
-pub trait Panel: Render + EventConsumer + Focusable {}
+pub trait Panel: Render + EventConsumer + Focusable {
+    /// Switches to `theme`, at startup and whenever the theme changes.
+    fn set_theme(&mut self, _theme: &Theme) {}
+
+    /// Picks up settings from `config`, at startup and whenever it is
+    /// reloaded.
+    fn set_config(&mut self, _config: &Config) {}
+
+    /// Whether the panel looks different from its last render. Panels that
+    /// don't track it are drawn every frame.
+    fn is_dirty(&self) -> bool {
+        true
+    }
+
+    /// Asks for a render next frame, for changes the panel can't see itself
+    /// such as a new font.
+    fn mark_dirty(&mut self) {}
+
+    /// Shows `message`, such as a config error, until the next input.
+    fn set_message(&mut self, _message: String) {}
+
+    /// Advances state that changes with time, such as the cursor blink.
+    /// Returns how long until it changes next, so the event loop knows how
+    /// long it can sleep.
+    fn tick(&mut self) -> Option<Duration> {
+        None
+    }
+}
 
 impl Debug for dyn Panel {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -16,7 +43,7 @@ pub trait Render {
     fn id(&self) -> String;
     fn render(
         &mut self,
-        atlas: &mut FontAtlas2,
+        atlas: &mut FontAtlas,
         font: &Font,
         canvas: &mut Canvas<Window>,
         rect: Rect,
@@ -25,6 +52,7 @@ pub trait Render {
 
 pub trait EventConsumer {
     fn consume_event(&mut self, event: &Event);
+    fn run_command(&mut self, command: Command);
 }
 
 pub trait Focusable {
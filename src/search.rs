@@ -0,0 +1,203 @@
+use std::{ops::Range, time::Instant};
+
+use regex::Regex;
+
+use crate::buffer::Buffer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchField {
+    Query,
+    Replacement,
+}
+
+/// State of the find/replace bar of a `TextArea`.
+pub struct Search {
+    pub query: String,
+    pub replacement: String,
+    /// Treat `query` as a regular expression instead of literal text.
+    pub regex: bool,
+    /// Field that receives typed text.
+    pub field: SearchField,
+    /// Char ranges of every match, in document order.
+    pub matches: Vec<Range<usize>>,
+    pub current: Option<usize>,
+    /// Char index the search was opened at. Refining the query jumps to
+    /// the first match after it rather than after the previous match.
+    pub origin: usize,
+    pub error: Option<String>,
+    compiled: Option<Regex>,
+    /// When the buffer last changed under `matches`. Finding them again
+    /// scans the whole text, so it waits for a pause in typing rather
+    /// than running on every keystroke.
+    stale_since: Option<Instant>,
+}
+
+impl Search {
+    pub fn new(origin: usize) -> Self {
+        Search {
+            query: String::new(),
+            replacement: String::new(),
+            regex: false,
+            field: SearchField::Query,
+            matches: Vec::new(),
+            current: None,
+            origin,
+            error: None,
+            compiled: None,
+            stale_since: None,
+        }
+    }
+
+    /// Recompiles the query and finds every match in `buffer`.
+    pub fn update(&mut self, buffer: &Buffer) {
+        self.matches.clear();
+        self.current = None;
+        self.error = None;
+        self.compiled = None;
+        self.stale_since = None;
+
+        if self.query.is_empty() {
+            return;
+        }
+        let pattern = if self.regex {
+            self.query.clone()
+        } else {
+            regex::escape(&self.query)
+        };
+        let re = match Regex::new(&pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return;
+            }
+        };
+
+        // Matches come back in order, so byte offsets can be turned into
+        // char indices with a single running count.
+        let text = buffer.to_string();
+        let (mut byte, mut char) = (0, 0);
+        let mut to_char = |b: usize| {
+            char += text[byte..b].chars().count();
+            byte = b;
+            char
+        };
+        for m in re.find_iter(&text) {
+            let start = to_char(m.start());
+            let end = to_char(m.end());
+            self.matches.push(start..end);
+        }
+        self.compiled = Some(re);
+    }
+
+    /// Notes that the buffer changed, so `matches` may be off until the
+    /// next `update`.
+    pub fn mark_stale(&mut self) {
+        self.stale_since = Some(Instant::now());
+    }
+
+    /// When the buffer changed under `matches`, if it did since `update`.
+    pub fn stale_since(&self) -> Option<Instant> {
+        self.stale_since
+    }
+
+    /// Makes the first match at or after `idx` current, wrapping around.
+    pub fn first_from(&mut self, idx: usize) -> Option<Range<usize>> {
+        if self.matches.is_empty() {
+            return None;
+        }
+        let i = self.matches.partition_point(|m| m.start < idx);
+        self.select(i % self.matches.len())
+    }
+
+    pub fn next_match(&mut self) -> Option<Range<usize>> {
+        let i = self.current.map_or(0, |i| i + 1);
+        self.select(i % self.matches.len().max(1))
+    }
+
+    pub fn prev_match(&mut self) -> Option<Range<usize>> {
+        let len = self.matches.len().max(1);
+        let i = self.current.map_or(len - 1, |i| (i + len - 1) % len);
+        self.select(i)
+    }
+
+    fn select(&mut self, i: usize) -> Option<Range<usize>> {
+        let m = self.matches.get(i)?.clone();
+        self.current = Some(i);
+        Some(m)
+    }
+
+    /// Whether the char at `idx` is part of a match.
+    pub fn is_match(&self, idx: usize) -> bool {
+        self.matches
+            .binary_search_by(|m| {
+                if m.end <= idx {
+                    std::cmp::Ordering::Less
+                } else if m.start > idx {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    /// Replacement text for each match, in the same order as `matches`.
+    /// In regex mode `$1`, `$name` and friends expand to capture groups.
+    pub fn replacements(&self, buffer: &Buffer) -> Vec<String> {
+        let Some(re) = &self.compiled else {
+            return Vec::new();
+        };
+        if !self.regex {
+            return vec![self.replacement.clone(); self.matches.len()];
+        }
+        let text = buffer.to_string();
+        re.captures_iter(&text)
+            .map(|caps| {
+                let mut out = String::new();
+                caps.expand(&self.replacement, &mut out);
+                out
+            })
+            .collect()
+    }
+
+    /// Replacement text for match `i` alone. Only the match itself is
+    /// matched again for capture groups, not the whole text.
+    pub fn replacement(&self, buffer: &Buffer, i: usize) -> String {
+        let (Some(re), Some(m)) = (&self.compiled, self.matches.get(i)) else {
+            return String::new();
+        };
+        if !self.regex {
+            return self.replacement.clone();
+        }
+        let text = buffer.slice(m.clone()).to_string();
+        match re.captures(&text) {
+            Some(caps) => {
+                let mut out = String::new();
+                caps.expand(&self.replacement, &mut out);
+                out
+            }
+            // Needed what came before or after, look at it all
+            None => self.replacements(buffer).swap_remove(i),
+        }
+    }
+
+    /// One line summary drawn in place of the `line:col` info.
+    pub fn status(&self) -> String {
+        let caret = |field| if self.field == field { "_" } else { "" };
+        let mode = if self.regex { "regex" } else { "find" };
+        let count = match (&self.error, self.current) {
+            (Some(_), _) => String::from("invalid regex"),
+            (None, Some(i)) => format!("{}/{}", i + 1, self.matches.len()),
+            (None, None) => format!("{} matches", self.matches.len()),
+        };
+        format!(
+            "{}: {}{}  replace: {}{}  [{}]",
+            mode,
+            self.query,
+            caret(SearchField::Query),
+            self.replacement,
+            caret(SearchField::Replacement),
+            count
+        )
+    }
+}
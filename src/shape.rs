@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use ab_glyph::{Font as _, FontRef, PxScale};
+use rustybuzz::UnicodeBuffer;
+
+use crate::atlas::replacement_width;
+
+/// A font file, loaded once for both shaping and rasterizing glyphs.
+pub struct Face {
+    /// Path the face was loaded from, which also tells faces apart in the
+    /// glyph cache.
+    pub name: String,
+    pub font: FontRef<'static>,
+    /// The same font parsed for shaping.
+    hb: rustybuzz::Face<'static>,
+}
+
+impl Face {
+    pub fn load(path: &str) -> Result<Face, String> {
+        let data = font_data(path)?;
+        let font = FontRef::try_from_slice(data).map_err(|e| format!("{}: {}", path, e))?;
+        let hb = rustybuzz::Face::from_slice(data, 0)
+            .ok_or_else(|| format!("{}: can't shape with this font", path))?;
+        Ok(Face {
+            name: String::from(path),
+            font,
+            hb,
+        })
+    }
+
+    /// Pixels per font unit at `size` points. Like SDL_ttf, a point is a
+    /// pixel, so the em square is `size` pixels across.
+    pub fn scale(&self, size: u16) -> f32 {
+        size as f32 / self.font.units_per_em().unwrap_or(1000.0)
+    }
+
+    /// The same size as ab_glyph measures it, by line height.
+    pub fn px_scale(&self, size: u16) -> PxScale {
+        PxScale::from(self.scale(size) * self.font.height_unscaled())
+    }
+
+    fn has(&self, ch: char) -> bool {
+        self.font.glyph_id(ch).0 != 0
+    }
+}
+
+/// One glyph of shaped text, positioned in pixels from the start of the
+/// text.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapedGlyph {
+    /// Index of the face the glyph comes from, `None` for chars no face
+    /// has, which are drawn as a replacement box.
+    pub face: Option<usize>,
+    /// Glyph ID in `face`, or the codepoint for a replacement box.
+    pub id: u32,
+    /// Char index in the text of the first char this glyph draws. Glyphs
+    /// of a ligature or of a char with combining marks share it.
+    pub cluster: usize,
+    /// Pen position.
+    pub x: f32,
+    /// Where the glyph sits relative to the pen, up being positive.
+    pub x_offset: f32,
+    pub y_offset: f32,
+    pub advance: f32,
+}
+
+/// Shapes `text` into glyphs at `size` points. Chars go to the first face
+/// that has them, and each run of chars from the same face is shaped on
+/// its own, so kerning and ligatures hold within a run.
+pub fn shape(faces: &[Face], size: u16, text: &str) -> Vec<ShapedGlyph> {
+    let mut glyphs = Vec::new();
+    let mut x = 0.0;
+    let mut runs: Vec<(Option<usize>, usize, usize)> = Vec::new();
+    for (i, (byte, ch)) in text.char_indices().enumerate() {
+        // Combining marks stay with the char they go on
+        let face = match runs.last() {
+            Some((Some(face), ..)) if joins_previous(ch) && faces[*face].has(ch) => Some(*face),
+            _ => faces.iter().position(|f| f.has(ch)),
+        };
+        match runs.last_mut() {
+            Some((last, ..)) if *last == face => (),
+            _ => runs.push((face, byte, i)),
+        }
+    }
+
+    for (n, &(face, start, first_char)) in runs.iter().enumerate() {
+        let end = runs.get(n + 1).map_or(text.len(), |r| r.1);
+        let run = &text[start..end];
+        let Some(i) = face else {
+            let advance = faces.first().map_or(0.0, |f| {
+                f.font.h_advance_unscaled(f.font.glyph_id('0')) * f.scale(size)
+            });
+            for (j, ch) in run.chars().enumerate() {
+                let advance = replacement_width(advance as i32, ch) as f32;
+                glyphs.push(ShapedGlyph {
+                    face: None,
+                    id: ch as u32,
+                    cluster: first_char + j,
+                    x,
+                    x_offset: 0.0,
+                    y_offset: 0.0,
+                    advance,
+                });
+                x += advance;
+            }
+            continue;
+        };
+
+        let face = &faces[i];
+        let mut buffer = UnicodeBuffer::new();
+        buffer.push_str(run);
+        // Text is laid out in logical order, a char per cell, so right to
+        // left runs are shaped left to right too. Their glyphs then come
+        // out in char order rather than reversed.
+        buffer.set_direction(rustybuzz::Direction::LeftToRight);
+        let shaped = rustybuzz::shape(&face.hb, &[], buffer);
+        let scale = face.scale(size);
+        for (info, pos) in shaped.glyph_infos().iter().zip(shaped.glyph_positions()) {
+            let cluster = first_char + run[..info.cluster as usize].chars().count();
+            glyphs.push(ShapedGlyph {
+                face: Some(i),
+                id: info.glyph_id,
+                cluster,
+                x,
+                x_offset: pos.x_offset as f32 * scale,
+                y_offset: pos.y_offset as f32 * scale,
+                advance: pos.x_advance as f32 * scale,
+            });
+            x += pos.x_advance as f32 * scale;
+        }
+    }
+    glyphs
+}
+
+/// The bytes of the font file at `path`, read on first use and kept for
+/// the rest of the run, so faces parsed from them can be kept as well.
+/// Reloading the config reuses them instead of reading the file again.
+fn font_data(path: &str) -> Result<&'static [u8], String> {
+    static LOADED: OnceLock<Mutex<HashMap<String, &'static [u8]>>> = OnceLock::new();
+    let mut loaded = LOADED.get_or_init(Default::default).lock().unwrap();
+    if let Some(data) = loaded.get(path) {
+        return Ok(data);
+    }
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let data: &'static [u8] = Box::leak(data.into_boxed_slice());
+    loaded.insert(String::from(path), data);
+    Ok(data)
+}
+
+/// Combining marks, variation selectors and joiners, which modify the
+/// char before them and have to be shaped along with it.
+fn joins_previous(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{0300}'..='\u{036f}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{200c}'..='\u{200d}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{fe00}'..='\u{fe0f}'
+            | '\u{fe20}'..='\u{fe2f}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn face() -> Option<Face> {
+        [
+            "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+            "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+        ]
+        .into_iter()
+        .find_map(|path| Face::load(path).ok())
+    }
+
+    #[test]
+    fn right_to_left_text_keeps_char_order() {
+        let Some(face) = face() else {
+            return;
+        };
+        let text = "مرحبا ";
+        let glyphs = shape(&[face], 16, text);
+        let clusters: Vec<usize> = glyphs.iter().map(|g| g.cluster).collect();
+        assert!(clusters.windows(2).all(|w| w[0] <= w[1]), "{:?}", clusters);
+        assert_eq!(clusters.last(), Some(&(text.chars().count() - 1)));
+        assert!(glyphs.windows(2).all(|w| w[0].x <= w[1].x));
+    }
+}
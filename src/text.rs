@@ -1,30 +1,107 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    ops::Range,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use sdl2::{
-    event::Event,
-    keyboard::Keycode,
-    pixels::{Color, PixelFormatEnum},
+    clipboard::ClipboardUtil,
+    event::{Event, EventSender},
     rect::Rect,
-    render::{Canvas, Texture},
+    render::Canvas,
     ttf::Font,
     video::Window,
+    VideoSubsystem,
 };
 
 use crate::{
-    atlas::FontAtlas2,
+    atlas::FontAtlas,
+    buffer::{normalize_line_endings, Buffer, Grapheme},
+    config::Config,
+    encoding::FileEncoding,
+    highlight::{Highlighter, Language},
+    history::{Edit, EditKind, History},
+    keymap::Command,
     panels::{EventConsumer, Focusable, Panel, Render},
+    search::{Search, SearchField},
+    shape::ShapedGlyph,
+    theme::Theme,
+    vim::{self, Action, InsertAt, Key, Mode, Motion, Operator, Vim},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct CursorPosition {
     pub line: usize,
     pub col: usize,
 }
 
+/// A caret plus the fixed end of its selection, if it has one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub pos: CursorPosition,
+    pub anchor: Option<CursorPosition>,
+}
+
+impl Cursor {
+    pub fn new(pos: CursorPosition) -> Self {
+        Cursor { pos, anchor: None }
+    }
+
+    /// Ordered start and end of the selection, if it is not empty.
+    pub fn selection(&self) -> Option<(CursorPosition, CursorPosition)> {
+        let anchor = self.anchor?;
+        match anchor.cmp(&self.pos) {
+            std::cmp::Ordering::Less => Some((anchor, self.pos)),
+            std::cmp::Ordering::Greater => Some((self.pos, anchor)),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    /// Ordered start and end of everything the cursor covers, which is
+    /// just its position when nothing is selected.
+    fn span(&self) -> (CursorPosition, CursorPosition) {
+        self.selection().unwrap_or((self.pos, self.pos))
+    }
+
+    /// Grows this cursor to also cover `other`, keeping its direction.
+    fn merge(&mut self, other: Cursor) {
+        let (start, end) = self.span();
+        let (other_start, other_end) = other.span();
+        let (start, end) = (start.min(other_start), end.max(other_end));
+        let forward = self.anchor.is_none_or(|anchor| anchor <= self.pos);
+
+        *self = if start == end {
+            Cursor::new(start)
+        } else if forward {
+            Cursor {
+                pos: end,
+                anchor: Some(start),
+            }
+        } else {
+            Cursor {
+                pos: start,
+                anchor: Some(end),
+            }
+        };
+    }
+}
+
+/// How long the cursor stays shown, then hidden, while the panel is idle.
+const BLINK: Duration = Duration::from_millis(500);
+
+/// Pause in editing after which the open search finds its matches again.
+const SEARCH_DELAY: Duration = Duration::from_millis(200);
+
+/// Gap between the top of the panel and the first line of text.
+const TEXT_TOP: u32 = 10;
+
 pub struct Viewport {
     pub cols: usize,
     pub cur_col: usize,
     pub cur_line: usize,
+    /// First visual row of `cur_line` shown, when soft wrapping.
+    pub cur_row: usize,
     pub lines: usize,
 }
 
@@ -34,12 +111,67 @@ impl Viewport {
     }
 }
 
+/// What the encoding prompt does with the encoding typed into it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EncodingAction {
+    Reopen,
+    Save,
+}
+
+/// The info line asking for an encoding by label, such as `latin1`,
+/// while one is typed.
+struct EncodingPrompt {
+    action: EncodingAction,
+    label: String,
+}
+
 pub struct TextArea {
-    pub cursor_pos: CursorPosition,
-    pub text: String,
+    /// Sorted by position and never overlapping.
+    pub cursors: Vec<Cursor>,
+    /// Index into `cursors` of the one the viewport follows.
+    pub primary: usize,
+    pub text: Buffer,
     pub viewport: Viewport,
     pub focused: bool,
     pub filepath: String,
+    pub history: History,
+    /// Find/replace bar, shown while it is open.
+    pub search: Option<Search>,
+    pub highlighter: Highlighter,
+    /// Number lines relative to the cursor line instead of from the top.
+    pub relative_numbers: bool,
+    /// Soft wrap lines wider than the panel instead of scrolling sideways.
+    pub wrap: bool,
+    /// Vim-style modal editing, on top of the regular bindings when enabled.
+    pub vim: Option<Vim>,
+    /// Columns between tab stops.
+    pub tab_width: usize,
+    /// Tab inserts spaces up to the next tab stop rather than a tab.
+    pub expand_tabs: bool,
+    /// `expand_tabs` came from the file's own indentation or was toggled,
+    /// so the config doesn't override it.
+    indent_known: bool,
+    /// Why the file couldn't be opened, such as it being binary. Saving
+    /// is refused while set, so the file isn't overwritten.
+    load_error: Option<String>,
+    /// Outcome of the last save or reopen that went wrong, or an error
+    /// from elsewhere, shown in the info line until the next input.
+    message: Option<String>,
+    encoding_prompt: Option<EncodingPrompt>,
+    pub theme: Theme,
+    /// Where the panel was last drawn, to map mouse clicks back to text.
+    rect: Rect,
+    gutter_width: u32,
+    line_height: u32,
+    clipboard: ClipboardUtil,
+    /// To post `Quit` for `:q`.
+    events: EventSender,
+    /// Something changed since the last render.
+    dirty: bool,
+    /// Cursor blink phase and when it last flipped. Input restarts it
+    /// shown.
+    blink_on: bool,
+    blink_at: Instant,
 }
 
 impl Focusable for TextArea {
@@ -48,9 +180,11 @@ impl Focusable for TextArea {
     }
     fn focus(&mut self) {
         self.focused = true;
+        self.touch();
     }
     fn unfocus(&mut self) {
         self.focused = false;
+        self.dirty = true;
     }
 }
 
@@ -59,47 +193,169 @@ impl EventConsumer for TextArea {
         if !self.is_focused() {
             return;
         }
+        if matches!(
+            event,
+            Event::MouseButtonDown { .. } | Event::TextInput { .. }
+        ) {
+            self.touch();
+        }
         match event {
-            sdl2::event::Event::TextInput { text, .. } => self.insert_char(dbg!(text.to_owned())),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Return),
-                ..
-            } => self.insert_char('\n'.to_string()),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::S),
-                keymod: sdl2::keyboard::Mod::LCTRLMOD,
-                ..
-            } => self.save(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Backspace),
-                ..
-            } => self.delete_char(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Left),
-                keymod: sdl2::keyboard::Mod::LCTRLMOD,
-                ..
-            } => self.home(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Right),
-                ..
-            } => self.next_char(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Left),
-                ..
-            } => self.prev_char(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Down),
-                ..
-            } => self.next_line(),
-            sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Up),
-                ..
-            } => self.prev_line(),
+            sdl2::event::Event::MouseButtonDown { x, y, .. } => self.click(*x, *y),
+            sdl2::event::Event::TextInput { text, .. } => {
+                if let Some(prompt) = self.encoding_prompt.as_mut() {
+                    prompt.label.push_str(text);
+                    return;
+                }
+                if let Some(search) = self.search.as_mut() {
+                    match search.field {
+                        SearchField::Query => search.query.push_str(text),
+                        SearchField::Replacement => search.replacement.push_str(text),
+                    }
+                    self.refresh_search();
+                    return;
+                }
+                // Vim takes keys as commands until one of them enters
+                // Insert mode, the rest is typed
+                let mut rest = text.as_str();
+                if self.vim.is_some() {
+                    while let Some(c) = rest.chars().next() {
+                        if !self.consume_vim_key(Key::Char(c)) {
+                            break;
+                        }
+                        rest = &rest[c.len_utf8()..];
+                    }
+                    if let Some(vim) = self.vim.as_mut() {
+                        vim.record_insert(rest);
+                    }
+                }
+                if !rest.is_empty() {
+                    self.insert_char(rest.to_owned())
+                }
+            }
             _ => (),
         };
     }
+
+    fn run_command(&mut self, command: Command) {
+        if !self.is_focused() {
+            return;
+        }
+        self.touch();
+        if self.encoding_prompt.is_some() {
+            self.run_prompt_command(command);
+            return;
+        }
+        if self.search.is_some() && self.run_search_command(command) {
+            return;
+        }
+        let vim_key = match command {
+            Command::Escape => Some(Key::Escape),
+            Command::Newline | Command::FindPrevious => Some(Key::Enter),
+            Command::Tab => Some(Key::Char('\t')),
+            Command::Backspace => Some(Key::Backspace),
+            _ => None,
+        };
+        if let Some(key) = vim_key.filter(|_| self.vim.is_some()) {
+            if self.consume_vim_key(key) {
+                return;
+            }
+        }
+        match command {
+            // Shift+Return only finds backwards in the search bar
+            Command::Newline | Command::FindPrevious => self.insert_char('\n'.to_string()),
+            Command::Tab => self.insert_tab(),
+            Command::ConvertLineEndings => {
+                self.text.set_line_ending(self.text.line_ending.next());
+            }
+            Command::SaveWithEncoding => self.open_encoding_prompt(EncodingAction::Save),
+            Command::ReopenWithEncoding => self.open_encoding_prompt(EncodingAction::Reopen),
+            Command::ToggleExpandTabs => {
+                self.expand_tabs = !self.expand_tabs;
+                self.indent_known = true;
+            }
+            Command::Save => self.save(),
+            Command::Find => self.open_search(),
+            Command::ToggleRelativeNumbers => self.relative_numbers = !self.relative_numbers,
+            Command::ToggleWrap => self.toggle_wrap(),
+            Command::ToggleVim => self.toggle_vim(),
+            Command::Redo => self.redo(),
+            Command::Undo => self.undo(),
+            Command::Copy => self.copy(),
+            Command::Cut => self.cut(),
+            Command::Paste => self.paste(),
+            Command::Backspace => self.delete_char(),
+            Command::Escape => self.collapse_cursors(),
+            Command::AddCursorAbove => self.add_cursor(true),
+            Command::AddCursorBelow => self.add_cursor(false),
+            Command::LineStart => self.move_cursor(false, Self::home),
+            Command::Right => self.move_cursor(false, Self::next_char),
+            Command::Left => self.move_cursor(false, Self::prev_char),
+            Command::Down => self.move_cursor(false, Self::next_line),
+            Command::Up => self.move_cursor(false, Self::prev_line),
+            Command::SelectLineStart => self.move_cursor(true, Self::home),
+            Command::SelectRight => self.move_cursor(true, Self::next_char),
+            Command::SelectLeft => self.move_cursor(true, Self::prev_char),
+            Command::SelectDown => self.move_cursor(true, Self::next_line),
+            Command::SelectUp => self.move_cursor(true, Self::prev_line),
+            _ => (),
+        }
+    }
+}
+impl Panel for TextArea {
+    fn set_theme(&mut self, theme: &Theme) {
+        self.theme = theme.clone();
+        self.dirty = true;
+    }
+
+    fn set_config(&mut self, config: &Config) {
+        self.tab_width = config.tab_width.max(1);
+        if !self.indent_known {
+            self.expand_tabs = config.expand_tabs;
+        }
+        self.dirty = true;
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+        self.dirty = true;
+    }
+
+    fn tick(&mut self) -> Option<Duration> {
+        let mut search_wait = None;
+        if let Some(search) = self.search.as_mut() {
+            if let Some(since) = search.stale_since() {
+                let elapsed = since.elapsed();
+                if elapsed < SEARCH_DELAY {
+                    search_wait = Some(SEARCH_DELAY - elapsed);
+                } else {
+                    search.update(&self.text);
+                    self.dirty = true;
+                }
+            }
+        }
+        if !self.focused {
+            return search_wait;
+        }
+        let elapsed = self.blink_at.elapsed();
+        let blink_wait = if elapsed < BLINK {
+            BLINK - elapsed
+        } else {
+            self.blink_on = !self.blink_on;
+            self.blink_at = Instant::now();
+            self.dirty = true;
+            BLINK
+        };
+        Some(search_wait.map_or(blink_wait, |wait| wait.min(blink_wait)))
+    }
 }
-impl Panel for TextArea {}
 
 impl Render for TextArea {
     fn id(&self) -> String {
@@ -108,220 +364,1513 @@ impl Render for TextArea {
 
     fn render(
         &mut self,
-        atlas: &mut FontAtlas2,
+        atlas: &mut FontAtlas,
         font: &Font,
         canvas: &mut Canvas<Window>,
         rect: Rect,
     ) {
         // dbg!(&self.filepath, &self.text);
-        let tc = canvas.texture_creator();
-        let mut x = 0;
-        let mut y = 10;
+        self.dirty = false;
+        let mut y = TEXT_TOP;
 
-        canvas.set_draw_color(Color::RGBA(50, 48, 47, 255));
+        canvas.set_draw_color(self.theme.background);
         canvas.clear();
 
-        let fg = Color::RGBA(253, 244, 193, 255);
+        let fg = self.theme.foreground;
+        let selection_bg = self.theme.selection;
+        let match_bg = self.theme.search_match;
+        let gutter_bg = self.theme.gutter_background;
+        let gutter_fg = self.theme.gutter_foreground;
+        let cursor_bg = self.theme.cursor;
+
+        let line_height = font.height() as u32;
+        let digits = self.text.len_lines().to_string().len();
+        let advance = atlas.advance('0');
+        let gutter_width = (digits as u32 + 1) * advance;
+        self.rect = rect;
+        self.gutter_width = gutter_width;
+        self.line_height = line_height;
 
-        let mut h = 0;
+        // Leave the last row for the info line.
+        let text_height = rect.height().saturating_sub(TEXT_TOP + line_height + 1);
+        let text_width = rect.width().saturating_sub(gutter_width);
+        self.fit_viewport(text_width, text_height, advance);
+
+        canvas.set_draw_color(gutter_bg);
+        canvas
+            .fill_rect(Rect::new(0, 0, gutter_width, rect.height()))
+            .unwrap();
+        let cursor_line = self.cursors[self.primary].pos.line;
+
+        if let Some(line) = self.text.take_changed_from() {
+            self.highlighter.invalidate_from(line);
+        }
 
-        for (lineno, tline) in self.text.split_inclusive('\n').enumerate() {
-            if !self.viewport.contains(lineno) {
-                continue;
+        let bottom = TEXT_TOP + self.viewport.lines as u32 * line_height;
+        let first_line = self.viewport.cur_line.min(self.text.len_lines());
+        for (lineno, tline) in self.text.lines_at(first_line).enumerate() {
+            let lineno = lineno + first_line;
+            let visible = if self.wrap {
+                y < bottom
+            } else {
+                self.viewport.contains(lineno)
+            };
+            if !visible {
+                break;
             }
-            let mut col = 0;
+            let skip_rows = if lineno == self.viewport.cur_line {
+                self.viewport.cur_row
+            } else {
+                0
+            };
+            let line_start = self.text.line_to_char(lineno);
+            let spans = self.highlighter.highlight_line(&self.text, lineno);
+
+            let number = if self.relative_numbers && lineno != cursor_line {
+                lineno.abs_diff(cursor_line)
+            } else {
+                lineno + 1
+            };
+            let number_fg = if lineno == cursor_line { fg } else { gutter_fg };
+            let mut numbers = atlas.run(0, y as i32);
+            atlas.push_str(
+                &mut numbers,
+                font,
+                &format!("{:>1$}", number, digits),
+                number_fg,
+            );
+            atlas.draw_run(canvas, font, &mut numbers);
+
+            // The newline is drawn as a space, so the cursor shows after
+            // the last char. Tabs are blank up to the next tab stop.
+            let chars: Vec<char> = tline
+                .chars()
+                .map(|c| if matches!(c, '\n' | '\t') { ' ' } else { c })
+                .collect();
+            // Graphemes with the screen column each starts at
+            let mut vcol = 0;
+            let graphemes: Vec<(usize, Grapheme)> = self
+                .text
+                .graphemes(lineno, self.tab_width)
+                .iter()
+                .map(|&g| {
+                    vcol += g.width;
+                    (vcol - g.width, g)
+                })
+                .collect();
+            let cols = self.viewport.cols.max(1);
+            let rows: Vec<(usize, &[(usize, Grapheme)])> = if self.wrap {
+                let rows = self.line_rows(lineno);
+                (skip_rows..rows)
+                    .map(|r| {
+                        let first = graphemes.partition_point(|(v, _)| *v < r * cols);
+                        let end = graphemes.partition_point(|(v, _)| *v < (r + 1) * cols);
+                        (r * cols, &graphemes[first..end])
+                    })
+                    .collect()
+            } else {
+                let left = self.viewport.cur_col;
+                let first = graphemes.partition_point(|(v, _)| *v < left);
+                let end = graphemes.partition_point(|(v, _)| *v < left + cols);
+                std::iter::once((left, &graphemes[first..end])).collect()
+            };
 
-            for c in tline.chars() {
-                let to_print = match c {
-                    '\n' => ' ',
-                    _ => c,
+            // The final line has no newline to draw the cursor on after its
+            // last char, so the cursor there gets a cell of its own
+            let end = CursorPosition {
+                line: lineno,
+                col: chars.len(),
+            };
+            let end_vcol = graphemes.last().map_or(0, |(v, g)| v + g.width);
+            let cursor_at_end = lineno == self.text.len_lines() - 1
+                && (self.blink_on || !self.focused)
+                && self.cursors.iter().any(|c| c.pos == end);
+
+            for (i, (left, row)) in rows.into_iter().enumerate() {
+                if i > 0 {
+                    y += line_height;
+                    if y >= bottom {
+                        break;
+                    }
+                }
+                if cursor_at_end && (left..left + cols).contains(&end_vcol) {
+                    let x = gutter_width + (end_vcol - left) as u32 * advance;
+                    canvas.set_draw_color(cursor_bg);
+                    canvas
+                        .fill_rect(Rect::new(x as i32, y as i32, advance, line_height))
+                        .unwrap();
+                }
+                let (Some((_, first)), Some((_, last))) = (row.first(), row.last()) else {
+                    continue;
                 };
-                let is_cursor = lineno == self.cursor_pos.line && col == self.cursor_pos.col;
-                let tex = atlas.draw_char(
-                    font,
-                    to_print,
-                    if is_cursor {
-                        Color::RGBA(0, 0, 0, 0)
+                let start = first.col;
+                let text: String = chars[start..last.col + last.len].iter().collect();
+                let len = last.col + last.len - start;
+                let glyphs = atlas.shape(&text);
+
+                // Shaping clusters, each with its first char and the pen
+                // position of its first glyph, and the cluster every char
+                // of the row falls in
+                let mut clusters: Vec<(usize, f32)> = Vec::new();
+                for g in &glyphs {
+                    if clusters.last().is_none_or(|c| c.0 != g.cluster) {
+                        clusters.push((g.cluster, g.x));
+                    }
+                }
+                let mut cluster_of = vec![0; len];
+                for (k, &(from, _)) in clusters.iter().enumerate() {
+                    let end = clusters.get(k + 1).map_or(len, |next| next.0);
+                    cluster_of[from..end].fill(k);
+                }
+
+                // Every char gets the cell of the grapheme it is part of, so
+                // wide chars take two cells whatever their advance says
+                let mut cell_x = vec![0; len];
+                let mut under_cursor = vec![false; clusters.len().max(1)];
+                for (v, g) in row {
+                    let x = gutter_width as i32 + ((v - left) as u32 * advance) as i32;
+                    cell_x[g.col - start..g.col - start + g.len].fill(x);
+                    let pos = CursorPosition {
+                        line: lineno,
+                        col: g.col,
+                    };
+                    if (self.blink_on || !self.focused) && self.cursors.iter().any(|c| c.pos == pos)
+                    {
+                        under_cursor[cluster_of[g.col - start]] = true;
+                    }
+                }
+
+                // Backgrounds go first, the glyphs on top in one go. A
+                // cursor on part of a ligature covers all of it, as the
+                // glyph is drawn inverted whole.
+                for (_, g) in row {
+                    let x = cell_x[g.col - start];
+                    let pos = CursorPosition {
+                        line: lineno,
+                        col: g.col,
+                    };
+                    let selected = self.cursors.iter().any(|c| {
+                        c.selection()
+                            .is_some_and(|(start, end)| (start..end).contains(&pos))
+                    });
+                    let found = self
+                        .search
+                        .as_ref()
+                        .is_some_and(|s| s.is_match(line_start + g.col));
+                    let cell_bg = if under_cursor[cluster_of[g.col - start]] {
+                        cursor_bg
+                    } else if selected {
+                        selection_bg
+                    } else if found {
+                        match_bg
                     } else {
-                        fg
-                    },
-                );
-                let q = tex.query();
-                let mut cursor: Texture;
-
-                let tex_final = if is_cursor {
-                    cursor = tc
-                        .create_texture_target(PixelFormatEnum::RGBA8888, q.width, q.height)
-                        .unwrap();
+                        continue;
+                    };
+                    canvas.set_draw_color(cell_bg);
                     canvas
-                        .with_texture_canvas(&mut cursor, |c| {
-                            c.set_draw_color(Color::RGBA(255, 255, 255, 255));
-                            c.clear();
-                            c.copy(tex, None, None).unwrap();
-                        })
+                        .fill_rect(Rect::new(
+                            x,
+                            y as i32,
+                            g.width as u32 * advance,
+                            line_height,
+                        ))
                         .unwrap();
-                    &cursor
-                } else {
-                    tex
-                };
+                }
 
-                let q = tex_final.query();
-                let w = q.width;
-                h = q.height;
-                canvas
-                    .copy(&tex_final, None, Some(Rect::new(x as i32, y as i32, w, h)))
-                    .unwrap();
-                x += w;
-                col += 1;
+                let mut run = atlas.run(0, y as i32);
+                for g in &glyphs {
+                    // Glyphs keep their shaped offset from the first glyph
+                    // of their cluster, which goes at the cluster's cell
+                    let k = cluster_of[g.cluster];
+                    let x = cell_x[g.cluster] as f32 + g.x - clusters[k].1;
+                    if x >= rect.width() as f32 {
+                        break;
+                    }
+                    let col = start + g.cluster;
+                    let color = if under_cursor[k] {
+                        // Inverted under the block cursor
+                        self.theme.background
+                    } else {
+                        match spans.iter().find(|span| span.cols.contains(&col)) {
+                            Some(span) => self.theme.scope(span.scope),
+                            None => fg,
+                        }
+                    };
+                    atlas.push_glyph(&mut run, font, &ShapedGlyph { x, ..*g }, color);
+                }
+                atlas.draw_run(canvas, font, &mut run);
             }
-            y += h;
-            x = 0;
+            y += line_height;
         }
 
-        let info = format!("{}:{}", self.cursor_pos.line + 1, self.cursor_pos.col + 1);
-        let info = atlas.draw_string(info, canvas, font, fg);
-        canvas
-            .copy(
-                &info,
-                None,
-                Some(Rect::new(
-                    0,
-                    (rect.height() - (info.query().height + 1)) as i32,
-                    info.query().width,
-                    info.query().height,
-                )),
-            )
-            .unwrap();
+        let cursor_pos = self.cursors[self.primary].pos;
+        let vcol = self.visual_col(cursor_pos.line, cursor_pos.col);
+        let mut info = format!(
+            "{}:{}  {}  {}",
+            cursor_pos.line + 1,
+            vcol + 1,
+            self.text.encoding,
+            self.text.line_ending
+        );
+        if self.cursors.len() > 1 {
+            info += &format!(" ({} cursors)", self.cursors.len());
+        }
+        if let Some(vim) = &self.vim {
+            info = match vim.mode {
+                Mode::Command(_) => vim.status(),
+                _ => format!("{}  {}", vim.status(), info),
+            };
+        }
+        if let Some(search) = &self.search {
+            info = search.status();
+        }
+        if let Some(e) = self.message.as_ref().or(self.load_error.as_ref()) {
+            info = format!("{}  {}", info, e);
+        }
+        if let Some(prompt) = &self.encoding_prompt {
+            info = match prompt.action {
+                EncodingAction::Save => format!("save as encoding: {}", prompt.label),
+                EncodingAction::Reopen if self.text.is_modified() => format!(
+                    "discard unsaved changes and reopen as encoding: {}",
+                    prompt.label
+                ),
+                EncodingAction::Reopen => format!("reopen as encoding: {}", prompt.label),
+            };
+        }
+        let mut run = atlas.run(0, (rect.height() - (line_height + 1)) as i32);
+        atlas.push_str(&mut run, font, &info, fg);
+        atlas.draw_run(canvas, font, &mut run);
     }
 }
 
 impl TextArea {
-    pub fn new(path: String) -> Self {
-        let text = std::fs::read_to_string(&path).unwrap_or_else(|_| String::from(" "));
+    pub fn new(path: String, video: &VideoSubsystem) -> Self {
+        let (text, encoding, load_error) = match read_file(&path, None) {
+            Ok(read) => read,
+            // A new file, created by the first save
+            Err(_) if !Path::new(&path).exists() => (String::new(), FileEncoding::default(), None),
+            Err(e) => (String::new(), FileEncoding::default(), Some(e)),
+        };
+        let language = Language::detect(&path, text.lines().next().unwrap_or(""));
+        let indent = detect_expand_tabs(&text);
+        let mut buffer = Buffer::new(&text);
+        buffer.encoding = encoding;
         TextArea {
-            text,
+            text: buffer,
             filepath: path,
-            cursor_pos: CursorPosition { line: 0, col: 0 },
+            cursors: vec![Cursor::new(CursorPosition { line: 0, col: 0 })],
+            primary: 0,
+            // Fitted to the panel on the first render
             viewport: Viewport {
                 cur_line: 0,
                 cur_col: 0,
+                cur_row: 0,
                 lines: 50,
                 cols: 80,
             },
             focused: false,
+            history: History::new(),
+            search: None,
+            highlighter: Highlighter::new(language),
+            relative_numbers: false,
+            wrap: language == Some(Language::Markdown),
+            vim: None,
+            tab_width: 4,
+            expand_tabs: indent.unwrap_or(true),
+            indent_known: indent.is_some(),
+            load_error,
+            message: None,
+            encoding_prompt: None,
+            theme: Theme::default(),
+            rect: Rect::new(0, 0, 1, 1),
+            gutter_width: 0,
+            line_height: 1,
+            clipboard: video.clipboard(),
+            events: video.sdl().event().unwrap().event_sender(),
+            dirty: true,
+            blink_on: true,
+            blink_at: Instant::now(),
         }
     }
 
+    /// Needs a redraw after input, with the cursor shown.
+    fn touch(&mut self) {
+        self.dirty = true;
+        self.message = None;
+        self.blink_on = true;
+        self.blink_at = Instant::now();
+    }
+
     fn translate_cp_to_idx(&self, cp: &CursorPosition) -> usize {
-        let mut accum = 0;
+        self.text.line_to_char(cp.line) + cp.col
+    }
 
-        for (idx, line) in self.text.split_inclusive('\n').enumerate() {
-            if idx == cp.line {
-                accum += cp.col;
-                break;
-            }
-            accum += line.chars().count();
+    /// Position of the char at `idx`, or of the start of the grapheme it
+    /// is part of.
+    fn translate_idx_to_cp(&self, idx: usize) -> CursorPosition {
+        let line = self.text.char_to_line(idx);
+        let col = idx - self.text.line_to_char(line);
+        let col = self.grapheme_at(line, col).map_or(col, |g| g.col);
+        CursorPosition { col, line }
+    }
+
+    /// The grapheme of `line` that char column `col` falls in, `None` past
+    /// the end of the line.
+    fn grapheme_at(&self, line: usize, col: usize) -> Option<Grapheme> {
+        self.text
+            .graphemes(line, self.tab_width)
+            .iter()
+            .find(|g| col < g.col + g.len)
+            .copied()
+    }
+
+    /// Index just after the grapheme at `idx`.
+    fn next_boundary(&self, idx: usize) -> usize {
+        let line = self.text.char_to_line(idx);
+        let line_start = self.text.line_to_char(line);
+        let end = match self.grapheme_at(line, idx - line_start) {
+            Some(g) => line_start + g.col + g.len,
+            None => idx + 1,
+        };
+        end.min(self.text.len_chars())
+    }
+
+    /// Index of the start of the grapheme before `idx`.
+    fn prev_boundary(&self, idx: usize) -> usize {
+        if idx == 0 {
+            return 0;
+        }
+        let line = self.text.char_to_line(idx - 1);
+        let line_start = self.text.line_to_char(line);
+        match self.grapheme_at(line, idx - 1 - line_start) {
+            Some(g) => line_start + g.col,
+            None => idx - 1,
         }
-        accum
     }
 
-    fn translate_idx_to_cp(&self, idx: usize) -> CursorPosition {
-        let mut accum = 0;
-        let mut col = 0;
-        let mut line = 0;
+    /// Screen column, in cells, of char column `col` of `line`.
+    fn visual_col(&self, line: usize, col: usize) -> usize {
+        self.text
+            .graphemes(line, self.tab_width)
+            .iter()
+            .take_while(|g| g.col < col)
+            .map(|g| g.width)
+            .sum()
+    }
 
-        for (i, l) in self.text.split_inclusive('\n').enumerate() {
-            if accum + l.char_indices().count() > idx {
-                col = idx - accum;
-                line = i;
-                return CursorPosition { col, line };
+    /// Position of the grapheme of `line` covering screen column `vcol`,
+    /// or the closest one.
+    fn at_visual(&self, line: usize, vcol: usize) -> CursorPosition {
+        let line = line.min(self.text.len_lines() - 1);
+        let mut x = 0;
+        for g in self.text.graphemes(line, self.tab_width).iter() {
+            x += g.width;
+            if x > vcol {
+                return self.clamp(line, g.col);
             }
-            accum += l.chars().count();
         }
-        CursorPosition { col, line }
+        self.clamp(line, usize::MAX)
     }
 
-    fn next_char(&mut self) {
-        self.goto(self.cursor_pos.line, self.cursor_pos.col + 1);
+    /// Moves every cursor with `motion`, extending their selections when
+    /// `extend` is set and dropping them otherwise.
+    fn move_cursor(&mut self, extend: bool, motion: fn(&Self, CursorPosition) -> CursorPosition) {
+        self.cursors = self
+            .cursors
+            .iter()
+            .map(|c| Cursor {
+                pos: motion(self, c.pos),
+                anchor: if extend {
+                    c.anchor.or(Some(c.pos))
+                } else {
+                    None
+                },
+            })
+            .collect();
+        self.normalize_cursors();
+        self.scroll_to_cursor();
     }
 
-    fn prev_char(&mut self) {
-        // we need to do this because of overflow
-        let f_c = if self.cursor_pos.col == 0 {
-            0
+    /// Sorts the cursors and merges the ones that overlap or sit at the
+    /// same position, keeping `primary` pointing at the cursor that
+    /// absorbed it. Selections that only touch stay apart, so each still
+    /// gets its own edit.
+    fn normalize_cursors(&mut self) {
+        let primary = self.cursors[self.primary].pos;
+        self.cursors.sort_by_key(|c| c.span().0);
+
+        let mut merged: Vec<Cursor> = Vec::with_capacity(self.cursors.len());
+        for cursor in self.cursors.drain(..) {
+            match merged.last_mut() {
+                Some(last) if cursor.pos == last.pos || cursor.span().0 < last.span().1 => {
+                    last.merge(cursor)
+                }
+                _ => merged.push(cursor),
+            }
+        }
+        self.cursors = merged;
+
+        self.primary = self
+            .cursors
+            .iter()
+            .position(|c| {
+                let (start, end) = c.span();
+                (start..=end).contains(&primary)
+            })
+            .unwrap_or(self.cursors.len() - 1);
+    }
+
+    /// Adds a cursor on the line above the first cursor or below the last
+    /// one, in the same column, and makes it the primary cursor.
+    fn add_cursor(&mut self, above: bool) {
+        let edge = if above {
+            self.cursors[0].pos
+        } else {
+            self.cursors[self.cursors.len() - 1].pos
+        };
+        let line = if above {
+            match edge.line.checked_sub(1) {
+                Some(line) => line,
+                None => return,
+            }
+        } else if edge.line + 1 < self.text.len_lines() {
+            edge.line + 1
         } else {
-            self.cursor_pos.col - 1
+            return;
         };
-        self.goto(self.cursor_pos.line, f_c);
+        let pos = self.clamp(line, edge.col);
+
+        self.cursors.push(Cursor::new(pos));
+        self.primary = self.cursors.len() - 1;
+        self.normalize_cursors();
+        self.scroll_to_cursor();
+    }
+
+    /// Drops every cursor but the primary one.
+    fn collapse_cursors(&mut self) {
+        self.cursors = vec![self.cursors[self.primary]];
+        self.primary = 0;
+    }
+
+    fn next_char(&self, cp: CursorPosition) -> CursorPosition {
+        let next = self
+            .grapheme_at(cp.line, cp.col)
+            .map_or(cp.col + 1, |g| g.col + g.len);
+        self.clamp(cp.line, next)
+    }
+
+    fn prev_char(&self, cp: CursorPosition) -> CursorPosition {
+        // we need to do this because of overflow
+        let f_c = if cp.col == 0 { 0 } else { cp.col - 1 };
+        self.clamp(cp.line, f_c)
+    }
+
+    /// Closest valid position to `new_l`:`new_c`.
+    fn clamp(&self, new_l: usize, new_c: usize) -> CursorPosition {
+        let line = new_l.clamp(0, self.text.len_lines() - 1);
+
+        // Up to the line break, or just past the last char of a final line
+        // without one, so text can be added at the end of it
+        let col = new_c.min(self.line_len(line));
+        // Never inside a grapheme
+        let col = self.grapheme_at(line, col).map_or(col, |g| g.col);
+
+        CursorPosition { line, col }
+    }
+
+    /// Collapses to a single cursor at `new_l`:`new_c`.
+    pub fn goto(&mut self, new_l: usize, new_c: usize) {
+        self.cursors = vec![Cursor::new(self.clamp(new_l, new_c))];
+        self.primary = 0;
+        self.scroll_to_cursor();
+    }
+
+    /// Sizes the viewport to the rows and columns that fit in `width` x
+    /// `height` pixels. Runs on every render, so it follows window resizes
+    /// and font size changes alike.
+    fn fit_viewport(&mut self, width: u32, height: u32, advance: u32) {
+        let lines = (height / self.line_height.max(1)).max(1) as usize;
+        let cols = (width / advance.max(1)).max(1) as usize;
+        if lines != self.viewport.lines || cols != self.viewport.cols {
+            self.viewport.lines = lines;
+            self.viewport.cols = cols;
+            self.scroll_to_cursor();
+        }
     }
 
-    fn goto(&mut self, new_l: usize, new_c: usize) {
-        dbg!("{} {}", new_l, new_c);
+    /// Scrolls the viewport so the primary cursor stays away from its edges.
+    /// Margins and jumps are fractions of the viewport, so small panels
+    /// still have room for the cursor and large ones don't scroll early.
+    fn scroll_to_cursor(&mut self) {
+        let cursor_pos = self.cursors[self.primary].pos;
+        let lines = self.viewport.lines;
+
+        // Wrapped lines can't scroll sideways; instead keep the top row
+        // between the rows that leave the cursor out of either margin.
+        if self.wrap {
+            let margin = lines / 8;
+            let vcol = self.visual_col(cursor_pos.line, cursor_pos.col);
+            let cursor = (cursor_pos.line, vcol / self.viewport.cols);
+            let lowest = self.rows_up(cursor, lines.saturating_sub(margin + 1));
+            let highest = self.rows_up(cursor, margin);
+            let top = (self.viewport.cur_line, self.viewport.cur_row);
+            (self.viewport.cur_line, self.viewport.cur_row) = top.clamp(lowest, highest);
+            self.viewport.cur_col = 0;
+            return;
+        }
+
+        let total = self.text.len_lines();
+        let margin = lines / 8;
+        let jump = (lines / 3).max(1);
+        let vp_v_reach = self.viewport.cur_line + lines;
 
-        let lines = self.text.split_inclusive('\n').collect::<Vec<&str>>();
-        self.cursor_pos.line = new_l.clamp(0, lines.len() - 1);
+        if cursor_pos.line + margin >= vp_v_reach && vp_v_reach < total {
+            let needed = cursor_pos.line + margin + 1 - lines;
+            self.viewport.cur_line = needed
+                .max(self.viewport.cur_line + jump)
+                .min(total - lines.min(total));
+        }
 
-        // Do this after so you dont overflow the usize
-        let line = lines[self.cursor_pos.line];
-        dbg!(new_c, 1, line.len());
-        self.cursor_pos.col = new_c.clamp(0, line.char_indices().count() - 1);
+        if cursor_pos.line < self.viewport.cur_line + margin && self.viewport.cur_line > 0 {
+            let needed = cursor_pos.line.saturating_sub(margin);
+            self.viewport.cur_line = needed.min(self.viewport.cur_line.saturating_sub(jump));
+        }
 
-        dbg!(&self.cursor_pos);
-        let vp_v_reach = self.viewport.cur_line + self.viewport.lines;
+        // Horizontally, keep the cursor `margin` columns away from either
+        // edge, except at the very start of the line.
+        let margin = self.viewport.cols / 8;
+        let vp_h_reach = self.viewport.cur_col + self.viewport.cols;
+        let vcol = self.visual_col(cursor_pos.line, cursor_pos.col);
 
-        if self.cursor_pos.line >= vp_v_reach - 3 && vp_v_reach < self.text.lines().count() {
-            self.viewport.cur_line += 10;
+        if vcol + margin >= vp_h_reach {
+            self.viewport.cur_col = vcol + margin + 1 - self.viewport.cols;
         }
 
-        if self.cursor_pos.line <= self.viewport.cur_line + 3 && self.viewport.cur_line > 0 {
-            self.viewport.cur_line -= std::cmp::min(self.viewport.cur_line, 10);
+        if vcol < self.viewport.cur_col + margin {
+            self.viewport.cur_col = vcol.saturating_sub(margin);
         }
     }
-    fn next_line(&mut self) {
-        self.goto(self.cursor_pos.line + 1, self.cursor_pos.col);
+
+    /// Up and down keep the screen column rather than the char column, so
+    /// the cursor moves straight across lines with wide chars.
+    fn next_line(&self, cp: CursorPosition) -> CursorPosition {
+        let vcol = self.visual_col(cp.line, cp.col);
+        if self.wrap {
+            let cols = self.viewport.cols;
+            if vcol / cols + 1 < self.line_rows(cp.line) {
+                return self.at_visual(cp.line, vcol + cols);
+            }
+            return self.at_visual(cp.line + 1, vcol % cols);
+        }
+        self.at_visual(cp.line + 1, vcol)
     }
 
-    fn prev_line(&mut self) {
+    fn prev_line(&self, cp: CursorPosition) -> CursorPosition {
+        let vcol = self.visual_col(cp.line, cp.col);
+        if self.wrap {
+            let cols = self.viewport.cols;
+            if vcol >= cols {
+                return self.at_visual(cp.line, vcol - cols);
+            }
+            if cp.line == 0 {
+                return cp;
+            }
+            let last_row = self.line_rows(cp.line - 1) - 1;
+            return self.at_visual(cp.line - 1, last_row * cols + vcol);
+        }
         // we need to do this because of overflow
-        let f_l = if self.cursor_pos.line == 0 {
-            0
-        } else {
-            self.cursor_pos.line - 1
-        };
-        self.goto(f_l, self.cursor_pos.col);
+        let f_l = if cp.line == 0 { 0 } else { cp.line - 1 };
+        self.at_visual(f_l, vcol)
+    }
+
+    pub fn toggle_wrap(&mut self) {
+        self.wrap = !self.wrap;
+        self.viewport.cur_col = 0;
+        self.viewport.cur_row = 0;
+        self.scroll_to_cursor();
+    }
+
+    /// Visual rows `line` takes up, which is only ever more than one when
+    /// wrapping. The line break counts as a column, the cursor can sit on it.
+    fn line_rows(&self, line: usize) -> usize {
+        if !self.wrap {
+            return 1;
+        }
+        let width: usize = self
+            .text
+            .graphemes(line, self.tab_width)
+            .iter()
+            .map(|g| g.width)
+            .sum();
+        // The final line has no line break, but still a cell for the
+        // cursor after its last char
+        let end = usize::from(line == self.text.len_lines() - 1);
+        (width + end).div_ceil(self.viewport.cols).max(1)
+    }
+
+    /// Moves `k` visual rows down from line and row `from`, stopping at the
+    /// last row of the buffer.
+    fn rows_down(&self, from: (usize, usize), mut k: usize) -> (usize, usize) {
+        let (mut line, mut row) = from;
+        loop {
+            let rows = self.line_rows(line);
+            if row + k < rows {
+                return (line, row + k);
+            }
+            if line + 1 >= self.text.len_lines() {
+                return (line, rows - 1);
+            }
+            k -= rows - row;
+            line += 1;
+            row = 0;
+        }
+    }
+
+    /// Moves `k` visual rows up from line and row `from`, stopping at the
+    /// first row of the buffer.
+    fn rows_up(&self, from: (usize, usize), mut k: usize) -> (usize, usize) {
+        let (mut line, mut row) = from;
+        loop {
+            if row >= k {
+                return (line, row - k);
+            }
+            if line == 0 {
+                return (0, 0);
+            }
+            k -= row + 1;
+            line -= 1;
+            row = self.line_rows(line) - 1;
+        }
     }
 
-    fn home(&mut self) {
-        self.goto(self.cursor_pos.line, 0);
+    fn home(&self, cp: CursorPosition) -> CursorPosition {
+        self.clamp(cp.line, 0)
+    }
+
+    /// Runs `op` once per cursor, front to back. Cursors after the current
+    /// one are shifted by however much the buffer grew or shrank, and all
+    /// the edits end up in a single undo step.
+    fn edit_each_cursor(&mut self, mut op: impl FnMut(&mut Self, Cursor) -> Cursor) {
+        let before = self.cursors.clone();
+        let mut offsets: Vec<(usize, Option<usize>)> = self
+            .cursors
+            .iter()
+            .map(|c| {
+                let anchor = c.anchor.map(|a| self.translate_cp_to_idx(&a));
+                (self.translate_cp_to_idx(&c.pos), anchor)
+            })
+            .collect();
+
+        let mut cursors = Vec::with_capacity(offsets.len());
+        for i in 0..offsets.len() {
+            let (pos, anchor) = offsets[i];
+            let cursor = Cursor {
+                pos: self.translate_idx_to_cp(pos),
+                anchor: anchor.map(|a| self.translate_idx_to_cp(a)),
+            };
+
+            let len = self.text.len_chars();
+            cursors.push(op(self, cursor));
+            let delta = self.text.len_chars() as isize - len as isize;
+
+            for (pos, anchor) in offsets[i + 1..].iter_mut() {
+                *pos = pos.saturating_add_signed(delta);
+                if let Some(anchor) = anchor {
+                    *anchor = anchor.saturating_add_signed(delta);
+                }
+            }
+        }
+
+        self.cursors = cursors;
+        self.normalize_cursors();
+        self.scroll_to_cursor();
+        self.history.commit(before, self.cursors.clone());
+        self.update_search();
     }
 
     pub fn insert_char(&mut self, ch: String) {
-        let idx = self.translate_cp_to_idx(&self.cursor_pos);
-        let char_boundary = self.text.char_indices().nth(idx).unwrap();
-        let (left, right) = self.text.split_at(char_boundary.0);
+        self.edit_each_cursor(|ta, cursor| ta.insert_at(cursor, &ch));
+    }
 
-        self.text = format!("{}{}{}", left, ch, right);
+    /// Indents at every cursor, with a tab or with spaces up to the next
+    /// tab stop.
+    pub fn insert_tab(&mut self) {
+        let indent = |ta: &Self, pos: CursorPosition| {
+            if !ta.expand_tabs {
+                return String::from("\t");
+            }
+            let vcol = ta.visual_col(pos.line, pos.col);
+            " ".repeat(ta.tab_width - vcol % ta.tab_width)
+        };
+        if self.vim.as_ref().is_some_and(|v| v.mode == Mode::Insert) {
+            let typed = indent(self, self.cursors[self.primary].pos);
+            if let Some(vim) = self.vim.as_mut() {
+                vim.record_insert(&typed);
+            }
+        }
+        self.edit_each_cursor(|ta, cursor| {
+            let text = indent(ta, cursor.pos);
+            ta.insert_at(cursor, &text)
+        });
+    }
 
-        if ch == "\n".to_string() {
-            self.next_line();
-            self.home();
-        } else {
-            self.next_char();
+    fn insert_at(&mut self, cursor: Cursor, text: &str) -> Cursor {
+        let cursor = self.delete_selection_at(cursor);
+        if text.is_empty() {
+            return cursor;
         }
+        let idx = self.translate_cp_to_idx(&cursor.pos);
+        self.text.insert(idx, text);
+
+        self.history.record(Edit {
+            kind: EditKind::Insert,
+            idx,
+            text: text.to_string(),
+        });
+
+        let cp = self.translate_idx_to_cp(idx + text.chars().count());
+        Cursor::new(self.clamp(cp.line, cp.col))
     }
 
     pub fn delete_char(&mut self) {
-        let idx = self.translate_cp_to_idx(&self.cursor_pos);
+        self.edit_each_cursor(Self::delete_before);
+    }
+
+    /// Backspace for one cursor: removes its selection, or the grapheme
+    /// before it when nothing is selected.
+    fn delete_before(&mut self, cursor: Cursor) -> Cursor {
+        if cursor.selection().is_some() {
+            return self.delete_selection_at(cursor);
+        }
+        let idx = self.translate_cp_to_idx(&cursor.pos);
         if idx == 0 {
+            return Cursor::new(cursor.pos);
+        }
+        let start = self.prev_boundary(idx);
+        let removed = self.text.slice(start..idx).to_string();
+        self.text.remove(start..idx);
+
+        self.history.record(Edit {
+            kind: EditKind::Delete,
+            idx: start,
+            text: removed,
+        });
+
+        Cursor::new(self.translate_idx_to_cp(start))
+    }
+
+    /// Removes the text selected by `cursor`, if any, and returns the
+    /// collapsed cursor at the start of where it was.
+    fn delete_selection_at(&mut self, cursor: Cursor) -> Cursor {
+        let Some((start, end)) = cursor.selection() else {
+            return Cursor::new(cursor.pos);
+        };
+        let range = self.translate_cp_to_idx(&start)..self.translate_cp_to_idx(&end);
+        let removed = self.text.slice(range.clone()).to_string();
+        self.text.remove(range.clone());
+
+        self.history.record(Edit {
+            kind: EditKind::Delete,
+            idx: range.start,
+            text: removed,
+        });
+
+        Cursor::new(start)
+    }
+
+    /// Selected text of every cursor, one selection per line.
+    fn selected_text(&self) -> Option<String> {
+        let selections: Vec<String> = self
+            .cursors
+            .iter()
+            .filter_map(|c| c.selection())
+            .map(|(start, end)| {
+                let range = self.translate_cp_to_idx(&start)..self.translate_cp_to_idx(&end);
+                self.text.slice(range).to_string()
+            })
+            .collect();
+        if selections.is_empty() {
+            None
+        } else {
+            Some(selections.join("\n"))
+        }
+    }
+
+    pub fn copy(&mut self) {
+        if let Some(selected) = self.selected_text() {
+            self.clipboard.set_clipboard_text(&selected).unwrap();
+        }
+    }
+
+    pub fn cut(&mut self) {
+        self.copy();
+        self.edit_each_cursor(Self::delete_selection_at);
+    }
+
+    pub fn paste(&mut self) {
+        if !self.clipboard.has_clipboard_text() {
+            return;
+        }
+        let text = self.clipboard.clipboard_text().unwrap();
+        self.insert_char(normalize_line_endings(&text));
+    }
+
+    pub fn undo(&mut self) {
+        let Some(tx) = self.history.undo() else {
+            return;
+        };
+        for edit in tx.edits.iter().rev() {
+            edit.revert(&mut self.text);
+        }
+        self.cursors = tx.cursors_before.clone();
+        self.primary = self.cursors.len() - 1;
+        self.scroll_to_cursor();
+        self.update_search();
+    }
+
+    pub fn redo(&mut self) {
+        let Some(tx) = self.history.redo() else {
+            return;
+        };
+        for edit in tx.edits.iter() {
+            edit.apply(&mut self.text);
+        }
+        self.cursors = tx.cursors_after.clone();
+        self.primary = self.cursors.len() - 1;
+        self.scroll_to_cursor();
+        self.update_search();
+    }
+
+    /// Selects `range` with a single cursor, scrolling it into view.
+    fn select_range(&mut self, range: Range<usize>) {
+        self.cursors = vec![Cursor {
+            pos: self.translate_idx_to_cp(range.end),
+            anchor: Some(self.translate_idx_to_cp(range.start)),
+        }];
+        self.primary = 0;
+        self.scroll_to_cursor();
+    }
+
+    /// Selects all of `line`, including its line break.
+    fn select_line(&mut self, line: usize) {
+        self.select_range(self.lines_range(line, line));
+    }
+
+    /// Char range of lines `first` to `last`, including the last line break.
+    fn lines_range(&self, first: usize, last: usize) -> Range<usize> {
+        let start = self.text.line_to_char(first);
+        let end = if last + 1 < self.text.len_lines() {
+            self.text.line_to_char(last + 1)
+        } else {
+            self.text.len_chars()
+        };
+        start..end
+    }
+
+    /// Mouse press at window coordinates `x`, `y`. Clicking a line number
+    /// selects that line.
+    fn click(&mut self, x: i32, y: i32) {
+        let x = x - self.rect.x();
+        let y = y - self.rect.y() - TEXT_TOP as i32;
+        if x < 0 || x >= self.gutter_width as i32 || y < 0 {
+            return;
+        }
+        let rows = y as usize / self.line_height as usize;
+        let line = if self.wrap {
+            self.rows_down((self.viewport.cur_line, self.viewport.cur_row), rows)
+                .0
+        } else {
+            self.viewport.cur_line + rows
+        };
+        if line < self.text.len_lines() {
+            self.select_line(line);
+        }
+    }
+
+    /// Handles commands while the find bar is open. Returns false for
+    /// the ones it leaves to the editor, such as cursor movement or saving.
+    fn run_search_command(&mut self, command: Command) -> bool {
+        self.catch_up_search();
+        let Some(search) = self.search.as_mut() else {
+            return false;
+        };
+        match command {
+            Command::Backspace => {
+                match search.field {
+                    SearchField::Query => search.query.pop(),
+                    SearchField::Replacement => search.replacement.pop(),
+                };
+                self.refresh_search();
+            }
+            Command::Newline | Command::FindPrevious => {
+                let found = if command == Command::FindPrevious {
+                    search.prev_match()
+                } else {
+                    search.next_match()
+                };
+                if let Some(range) = found {
+                    self.select_range(range);
+                }
+            }
+            Command::NextField | Command::Tab => {
+                search.field = match search.field {
+                    SearchField::Query => SearchField::Replacement,
+                    SearchField::Replacement => SearchField::Query,
+                }
+            }
+            Command::ToggleRegex => {
+                search.regex = !search.regex;
+                self.refresh_search();
+            }
+            Command::ReplaceAll => self.replace_all(),
+            Command::Replace => self.replace_current(),
+            Command::Escape => self.search = None,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn open_search(&mut self) {
+        let origin = self.translate_cp_to_idx(&self.cursors[self.primary].pos);
+        match self.search.as_mut() {
+            Some(search) => {
+                search.origin = origin;
+                search.field = SearchField::Query;
+            }
+            None => self.search = Some(Search::new(origin)),
+        }
+        self.refresh_search();
+    }
+
+    /// Notes that the buffer changed under the search. Its matches are
+    /// found again once editing pauses, see `tick`, or as soon as a
+    /// search command needs them.
+    fn update_search(&mut self) {
+        if let Some(search) = self.search.as_mut() {
+            search.mark_stale();
+        }
+    }
+
+    /// Finds the matches again now if the buffer changed since they were
+    /// last found.
+    fn catch_up_search(&mut self) {
+        if let Some(search) = self.search.as_mut() {
+            if search.stale_since().is_some() {
+                search.update(&self.text);
+            }
+        }
+    }
+
+    /// Re-runs the search after the query changed and selects the first
+    /// match after where the search was opened.
+    fn refresh_search(&mut self) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        search.update(&self.text);
+        if let Some(range) = search.first_from(search.origin) {
+            self.select_range(range);
+        }
+    }
+
+    /// Selects the first match at or after `idx`.
+    fn jump_to_match_from(&mut self, idx: usize) {
+        self.catch_up_search();
+        let found = self.search.as_mut().and_then(|s| s.first_from(idx));
+        if let Some(range) = found {
+            self.select_range(range);
+        }
+    }
+
+    pub fn replace_current(&mut self) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        let Some(i) = search.current else {
+            return;
+        };
+        let range = search.matches[i].clone();
+        let replacement = search.replacement(&self.text, i);
+
+        self.select_range(range);
+        self.insert_char(replacement);
+
+        let idx = self.translate_cp_to_idx(&self.cursors[self.primary].pos);
+        self.jump_to_match_from(idx);
+    }
+
+    /// Replaces every match as a single undo step.
+    pub fn replace_all(&mut self) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        if search.matches.is_empty() {
             return;
         }
-        let char_boundary = self.text.char_indices().nth(idx - 1).unwrap();
-        dbg!(self.text.remove(char_boundary.0));
-        let cp = self.translate_idx_to_cp(idx - 1);
-        self.goto(cp.line, cp.col);
+        let mut replacements = search.replacements(&self.text).into_iter();
+        self.cursors = search
+            .matches
+            .iter()
+            .map(|m| Cursor {
+                pos: self.translate_idx_to_cp(m.end),
+                anchor: Some(self.translate_idx_to_cp(m.start)),
+            })
+            .collect();
+        self.primary = 0;
+
+        self.edit_each_cursor(|ta, cursor| {
+            let replacement = replacements.next().unwrap_or_default();
+            ta.insert_at(cursor, &replacement)
+        });
+        self.collapse_cursors();
+    }
+
+    pub fn toggle_vim(&mut self) {
+        self.vim = match self.vim {
+            Some(_) => None,
+            None => Some(Vim::new()),
+        };
+        self.collapse_cursors();
+    }
+
+    /// Handles keys while modal editing is on. Insert mode only watches
+    /// what is typed, for `.`, and leaves the editing to the regular
+    /// commands, as do the other modes for keys they don't use.
+    fn consume_vim_key(&mut self, key: Key) -> bool {
+        let Some(mut vim) = self.vim.take() else {
+            return false;
+        };
+        let consumed = self.vim_key(&mut vim, key);
+        self.vim = Some(vim);
+        consumed
+    }
+
+    fn vim_key(&mut self, vim: &mut Vim, key: Key) -> bool {
+        if vim.mode == Mode::Insert {
+            match key {
+                Key::Escape => {
+                    vim.leave_insert();
+                    let pos = self.prev_char(self.cursors[self.primary].pos);
+                    self.goto(pos.line, pos.col);
+                    return true;
+                }
+                Key::Enter => vim.record_insert("\n"),
+                Key::Backspace => vim.record_backspace(),
+                // Typed text is recorded by the caller
+                Key::Char(_) => (),
+            }
+            return false;
+        }
+        if let Some(action) = vim.key(key) {
+            self.run_vim_action(vim, action);
+        }
+        true
+    }
+
+    fn run_vim_action(&mut self, vim: &mut Vim, action: Action) {
+        let cursor = self.cursors[self.primary];
+        let pos = cursor.pos;
+        let idx = self.translate_cp_to_idx(&pos);
+        match action {
+            Action::Move(motion, count) => {
+                let target = self.vim_motion(pos, motion, count);
+                if vim.mode == Mode::Visual {
+                    self.cursors = vec![Cursor {
+                        pos: target,
+                        anchor: cursor.anchor.or(Some(pos)),
+                    }];
+                    self.primary = 0;
+                    self.scroll_to_cursor();
+                } else {
+                    self.goto(target.line, target.col);
+                }
+            }
+            Action::Operate(op, motion, count) => {
+                // Like vim, `cw` leaves the space after the word alone
+                let motion = match (op, motion) {
+                    (Operator::Change, Motion::WordForward) => Motion::WordEnd,
+                    _ => motion,
+                };
+                let range = self.motion_range(pos, motion, count);
+                self.vim_operate(vim, op, range, motion.linewise());
+            }
+            Action::OperateLines(op, count) => {
+                let last = (pos.line + count - 1).min(self.text.len_lines() - 1);
+                let range = self.lines_range(pos.line, last);
+                self.vim_operate(vim, op, range, true);
+            }
+            Action::OperateSelection(op) => {
+                vim.mode = Mode::Normal;
+                // The char under the cursor is part of a visual selection,
+                // so one that starts and ends on the same char still covers it
+                let (start, end) = cursor.span();
+                let start = self.translate_cp_to_idx(&start);
+                let end = self.next_boundary(self.translate_cp_to_idx(&end));
+                self.vim_operate(vim, op, start..end, false);
+            }
+            Action::DeleteChar(count) => {
+                let line_end = self.text.line_to_char(pos.line) + self.line_len(pos.line);
+                let end = (0..count)
+                    .fold(idx, |i, _| self.next_boundary(i))
+                    .min(line_end);
+                if end > idx {
+                    self.vim_operate(vim, Operator::Delete, idx..end, false);
+                }
+            }
+            Action::Paste(count) => {
+                let text = vim.register.repeat(count);
+                if vim.register_linewise {
+                    let at = self.lines_range(pos.line, pos.line).end;
+                    let len = self.text.len_chars();
+                    let text = if at == len && (len == 0 || self.text.char(len - 1) != '\n') {
+                        format!("\n{}", text.trim_end_matches('\n'))
+                    } else {
+                        text
+                    };
+                    let at = self.translate_idx_to_cp(at);
+                    self.goto(at.line, at.col);
+                    self.insert_char(text);
+                    self.goto(pos.line + 1, 0);
+                } else if !text.is_empty() {
+                    let at =
+                        (idx + 1).min(self.text.line_to_char(pos.line) + self.line_len(pos.line));
+                    let at = self.translate_idx_to_cp(at);
+                    self.goto(at.line, at.col);
+                    self.insert_char(text);
+                    let end = self.prev_char(self.cursors[self.primary].pos);
+                    self.goto(end.line, end.col);
+                }
+            }
+            Action::Insert(at) => {
+                match at {
+                    InsertAt::Cursor => (),
+                    InsertAt::AfterCursor => {
+                        let col = self.next_char(pos).col.max(pos.col + 1);
+                        self.goto(pos.line, col.min(self.line_len(pos.line)));
+                    }
+                    InsertAt::LineStart => self.goto(pos.line, 0),
+                    InsertAt::LineEnd => self.goto(pos.line, self.line_len(pos.line)),
+                    InsertAt::LineBelow => {
+                        self.goto(pos.line, self.line_len(pos.line));
+                        self.insert_char('\n'.to_string());
+                    }
+                    InsertAt::LineAbove => {
+                        self.goto(pos.line, 0);
+                        self.insert_char('\n'.to_string());
+                        self.goto(pos.line, 0);
+                    }
+                }
+                vim.mode = Mode::Insert;
+            }
+            Action::Visual => {
+                vim.mode = Mode::Visual;
+                self.cursors = vec![Cursor {
+                    pos,
+                    anchor: Some(pos),
+                }];
+                self.primary = 0;
+            }
+            Action::Normal => {
+                vim.mode = Mode::Normal;
+                self.goto(pos.line, pos.col);
+            }
+            Action::Undo => self.undo(),
+            Action::Repeat => {
+                let Some((change, typed)) = vim.last_change.clone() else {
+                    return;
+                };
+                self.run_vim_action(vim, change);
+                if vim.mode == Mode::Insert {
+                    if !typed.is_empty() {
+                        self.insert_char(typed);
+                    }
+                    vim.leave_insert();
+                    let pos = self.prev_char(self.cursors[self.primary].pos);
+                    self.goto(pos.line, pos.col);
+                }
+            }
+            Action::Ex(cmd) => match cmd.trim() {
+                "w" => self.save(),
+                "q" => self.quit(),
+                "wq" | "x" => match self.write_file() {
+                    Ok(()) => self.quit(),
+                    Err(e) => self.message = Some(e),
+                },
+                "e" => self.reopen_unless_modified(None),
+                "e!" => self.reopen(None),
+                cmd => {
+                    if let Some((name, label)) = cmd.split_once(" ++enc=") {
+                        match (name.trim(), FileEncoding::for_label(label)) {
+                            ("e", Ok(encoding)) => self.reopen_unless_modified(Some(encoding)),
+                            ("e!", Ok(encoding)) => self.reopen(Some(encoding)),
+                            ("w", Ok(encoding)) => self.save_as(encoding),
+                            (_, Err(e)) => self.message = Some(e),
+                            _ => (),
+                        }
+                    } else if let Ok(line) = cmd.parse::<usize>() {
+                        self.goto(line.saturating_sub(1), 0);
+                    }
+                }
+            },
+        }
+    }
+
+    /// Where `motion`, repeated `count` times, takes the cursor at `cp`.
+    fn vim_motion(
+        &self,
+        cp: CursorPosition,
+        motion: Motion,
+        count: Option<usize>,
+    ) -> CursorPosition {
+        let last_line = {
+            let lines = self.text.len_lines();
+            // Skip the empty line the rope reports after a final '\n'
+            if lines > 1 && self.text.line(lines - 1).len_chars() == 0 {
+                lines - 2
+            } else {
+                lines - 1
+            }
+        };
+        match motion {
+            Motion::FirstLine => return self.clamp(count.map_or(0, |l| l.saturating_sub(1)), 0),
+            Motion::LastLine => {
+                return self.clamp(count.map_or(last_line, |l| l.saturating_sub(1)), 0)
+            }
+            Motion::LineStart => return self.home(cp),
+            Motion::LineEnd => {
+                let line = (cp.line + count.unwrap_or(1) - 1).min(last_line);
+                return self.clamp(line, self.line_len(line).saturating_sub(1));
+            }
+            _ => (),
+        }
+
+        let mut cp = cp;
+        for _ in 0..count.unwrap_or(1) {
+            let idx = self.translate_cp_to_idx(&cp);
+            cp = match motion {
+                Motion::Left => self.prev_char(cp),
+                Motion::Right => {
+                    let next = self.next_char(cp);
+                    if next.col < self.line_len(cp.line) {
+                        next
+                    } else {
+                        cp
+                    }
+                }
+                Motion::Up => self.prev_line(cp),
+                Motion::Down => self.next_line(cp),
+                Motion::WordForward => self.translate_idx_to_cp(vim::word_forward(&self.text, idx)),
+                Motion::WordBackward => {
+                    self.translate_idx_to_cp(vim::word_backward(&self.text, idx))
+                }
+                Motion::WordEnd => self.translate_idx_to_cp(vim::word_end(&self.text, idx)),
+                _ => cp,
+            };
+        }
+        cp
+    }
+
+    /// Chars an operator with `motion` acts on, from the cursor at `cp`.
+    fn motion_range(
+        &self,
+        cp: CursorPosition,
+        motion: Motion,
+        count: Option<usize>,
+    ) -> Range<usize> {
+        let target = self.vim_motion(cp, motion, count);
+        let (from, to) = (
+            self.translate_cp_to_idx(&cp),
+            self.translate_cp_to_idx(&target),
+        );
+        let (start, end) = (from.min(to), from.max(to));
+        if motion.linewise() {
+            return self.lines_range(cp.line.min(target.line), cp.line.max(target.line));
+        }
+        if motion.inclusive() {
+            return start..self.next_boundary(end);
+        }
+        // `dw` on the last word of a line stops at the line break
+        if motion == Motion::WordForward && target.line > cp.line {
+            return start..self.text.line_to_char(cp.line) + self.line_len(cp.line);
+        }
+        start..end
+    }
+
+    /// Yanks `range` into the register and, for `d` and `c`, deletes it.
+    fn vim_operate(&mut self, vim: &mut Vim, op: Operator, range: Range<usize>, linewise: bool) {
+        // `cc` keeps the line break, so there is a line left to type on
+        let range = if op == Operator::Change && linewise && range.end > range.start {
+            match self.text.char(range.end - 1) {
+                '\n' => range.start..range.end - 1,
+                _ => range,
+            }
+        } else {
+            range
+        };
+        vim.register = self.text.slice(range.clone()).to_string();
+        vim.register_linewise = linewise;
+
+        let start = self.translate_idx_to_cp(range.start);
+        match op {
+            Operator::Yank => self.goto(start.line, start.col),
+            Operator::Delete | Operator::Change => {
+                if !range.is_empty() {
+                    self.select_range(range);
+                    self.delete_char();
+                }
+                if op == Operator::Change {
+                    vim.mode = Mode::Insert;
+                } else {
+                    let pos = self.cursors[self.primary].pos;
+                    let col = if linewise { 0 } else { pos.col };
+                    self.goto(pos.line, col.min(self.line_len(pos.line).saturating_sub(1)));
+                }
+            }
+        }
+    }
+
+    /// Chars in `line`, not counting its line break.
+    fn line_len(&self, line: usize) -> usize {
+        let slice = self.text.line(line);
+        let mut len = slice.len_chars();
+        while len > 0 && matches!(slice.char(len - 1), '\n' | '\r') {
+            len -= 1;
+        }
+        len
+    }
+
+    fn quit(&mut self) {
+        self.events
+            .push_event(Event::Quit { timestamp: 0 })
+            .unwrap();
     }
 
     pub fn save(&mut self) {
-        std::fs::write(self.filepath.clone(), self.text.clone()).unwrap();
+        if let Err(e) = self.write_file() {
+            self.message = Some(e);
+        }
+    }
+
+    /// Saves in `encoding` from now on, keeping the old one if the text
+    /// can't be saved in it.
+    pub fn save_as(&mut self, encoding: FileEncoding) {
+        let previous = std::mem::replace(&mut self.text.encoding, encoding);
+        if let Err(e) = self.write_file() {
+            self.text.encoding = previous;
+            self.message = Some(e);
+        }
+    }
+
+    /// Encodes the whole text before touching the file, so a failure
+    /// leaves it as it was.
+    fn write_file(&mut self) -> Result<(), String> {
+        if let Some(e) = &self.load_error {
+            return Err(format!("{}, not saved", e));
+        }
+        let bytes = self.text.to_bytes()?;
+        std::fs::write(&self.filepath, bytes).map_err(|e| format!("{}: {}", self.filepath, e))?;
+        self.text.mark_saved();
+        Ok(())
+    }
+
+    /// `reopen`, unless that would throw away edits, which `:e!` is for.
+    fn reopen_unless_modified(&mut self, encoding: Option<FileEncoding>) {
+        if self.text.is_modified() {
+            self.message = Some(String::from("unsaved changes, :e! discards them"));
+        } else {
+            self.reopen(encoding);
+        }
+    }
+
+    /// Reads the file again as `encoding`, or as whatever it looks to be
+    /// in, dropping unsaved changes and the undo history. Naming an
+    /// encoding shows binary files, but they still can't be saved.
+    pub fn reopen(&mut self, encoding: Option<FileEncoding>) {
+        let (text, encoding, load_error) = match read_file(&self.filepath, encoding) {
+            Ok(read) => read,
+            Err(e) => {
+                self.message = Some(e);
+                return;
+            }
+        };
+        self.text = Buffer::new(&text);
+        self.text.encoding = encoding;
+        self.load_error = load_error;
+        self.history = History::new();
+        self.highlighter.invalidate_from(0);
+        self.cursors = vec![Cursor::new(CursorPosition { line: 0, col: 0 })];
+        self.primary = 0;
+        self.viewport.cur_line = 0;
+        self.viewport.cur_col = 0;
+        self.viewport.cur_row = 0;
+        // Matches of the old text may run past the end of the new one
+        self.update_search();
+    }
+
+    fn open_encoding_prompt(&mut self, action: EncodingAction) {
+        self.encoding_prompt = Some(EncodingPrompt {
+            action,
+            label: String::new(),
+        });
+    }
+
+    /// Edits and accepts the encoding prompt, which takes every command
+    /// while it is open.
+    fn run_prompt_command(&mut self, command: Command) {
+        let Some(prompt) = self.encoding_prompt.as_mut() else {
+            return;
+        };
+        match command {
+            Command::Backspace => {
+                prompt.label.pop();
+            }
+            Command::Escape => self.encoding_prompt = None,
+            Command::Newline => {
+                let prompt = self.encoding_prompt.take().unwrap();
+                match FileEncoding::for_label(&prompt.label) {
+                    Ok(encoding) if prompt.action == EncodingAction::Save => self.save_as(encoding),
+                    Ok(encoding) => self.reopen(Some(encoding)),
+                    Err(e) => self.message = Some(e),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Reads the file at `path` as `encoding`, or as whatever it looks to be
+/// encoded in. Also returns why the text can't be saved back, for binary
+/// files and bytes the encoding had no chars for.
+fn read_file(
+    path: &str,
+    encoding: Option<FileEncoding>,
+) -> Result<(String, FileEncoding, Option<String>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let detected = FileEncoding::detect(&bytes);
+    let mut encoding = match (encoding, &detected) {
+        (Some(encoding), _) => encoding,
+        (None, Ok(encoding)) => *encoding,
+        (None, Err(e)) => return Err(e.clone()),
+    };
+    let (text, lossy) = encoding.decode(&bytes);
+    let read_only = match detected {
+        Err(e) => Some(e),
+        Ok(_) if lossy => Some(format!("not valid {}", encoding.encoding.name())),
+        Ok(_) => None,
+    };
+    Ok((text, encoding, read_only))
+}
+
+/// Whether `text` indents with spaces rather than tabs, going by which of
+/// the two starts more lines. `None` when no line is indented.
+fn detect_expand_tabs(text: &str) -> Option<bool> {
+    let (mut tabs, mut spaces) = (0, 0);
+    for line in text.lines() {
+        if line.starts_with('\t') {
+            tabs += 1;
+        } else if line.starts_with("  ") {
+            spaces += 1;
+        }
     }
+    (tabs + spaces > 0).then_some(spaces > tabs)
 }
@@ -0,0 +1,344 @@
+use std::path::PathBuf;
+
+use sdl2::pixels::Color;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    config::{config_dir, ConfigError},
+    highlight::Scope,
+};
+
+/// Every color the editor draws with. Theme files are TOML with the same
+/// field names, colors as `"#rrggbb"` or `"#rrggbbaa"`; fields they leave
+/// out come from the default theme:
+///
+/// ```toml
+/// background = "#1d2021"
+///
+/// [syntax]
+/// comment = "#7c6f64"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    #[serde(skip)]
+    pub name: String,
+    /// Behind the panels, shows in the gaps between them.
+    #[serde(deserialize_with = "hex")]
+    pub window: Color,
+    #[serde(deserialize_with = "hex")]
+    pub background: Color,
+    #[serde(deserialize_with = "hex")]
+    pub foreground: Color,
+    #[serde(deserialize_with = "hex")]
+    pub cursor: Color,
+    #[serde(deserialize_with = "hex")]
+    pub selection: Color,
+    #[serde(rename = "match", deserialize_with = "hex")]
+    pub search_match: Color,
+    #[serde(deserialize_with = "hex")]
+    pub gutter_background: Color,
+    #[serde(deserialize_with = "hex")]
+    pub gutter_foreground: Color,
+    #[serde(deserialize_with = "hex")]
+    pub border: Color,
+    #[serde(deserialize_with = "hex")]
+    pub border_focused: Color,
+    pub syntax: SyntaxColors,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyntaxColors {
+    #[serde(deserialize_with = "hex")]
+    pub keyword: Color,
+    #[serde(rename = "type", deserialize_with = "hex")]
+    pub ty: Color,
+    #[serde(deserialize_with = "hex")]
+    pub function: Color,
+    #[serde(deserialize_with = "hex")]
+    pub string: Color,
+    #[serde(deserialize_with = "hex")]
+    pub number: Color,
+    #[serde(deserialize_with = "hex")]
+    pub constant: Color,
+    #[serde(deserialize_with = "hex")]
+    pub comment: Color,
+    #[serde(deserialize_with = "hex")]
+    pub preprocessor: Color,
+    #[serde(deserialize_with = "hex")]
+    pub property: Color,
+    #[serde(deserialize_with = "hex")]
+    pub heading: Color,
+    #[serde(deserialize_with = "hex")]
+    pub emphasis: Color,
+    #[serde(deserialize_with = "hex")]
+    pub code: Color,
+    #[serde(deserialize_with = "hex")]
+    pub link: Color,
+    #[serde(deserialize_with = "hex")]
+    pub punctuation: Color,
+}
+
+/// The `[colors]` table of the config file, from before there were
+/// themes. Colors it sets replace the theme's.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorOverrides {
+    #[serde(deserialize_with = "hex_opt")]
+    pub background: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub foreground: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub selection: Option<Color>,
+    #[serde(rename = "match", deserialize_with = "hex_opt")]
+    pub search_match: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub cursor: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub gutter_background: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub gutter_foreground: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub border: Option<Color>,
+    #[serde(deserialize_with = "hex_opt")]
+    pub border_focused: Option<Color>,
+}
+
+impl ColorOverrides {
+    pub fn apply(&self, theme: &mut Theme) {
+        for (color, over) in [
+            (&mut theme.background, self.background),
+            (&mut theme.foreground, self.foreground),
+            (&mut theme.selection, self.selection),
+            (&mut theme.search_match, self.search_match),
+            (&mut theme.cursor, self.cursor),
+            (&mut theme.gutter_background, self.gutter_background),
+            (&mut theme.gutter_foreground, self.gutter_foreground),
+            (&mut theme.border, self.border),
+            (&mut theme.border_focused, self.border_focused),
+        ] {
+            if let Some(over) = over {
+                *color = over;
+            }
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        gruvbox_dark()
+    }
+}
+
+impl Default for SyntaxColors {
+    fn default() -> Self {
+        gruvbox_dark().syntax
+    }
+}
+
+/// Themes that ship with the editor.
+const BUILTIN: &[&str] = &["gruvbox-dark", "gruvbox-light", "solarized-dark"];
+
+fn builtin(name: &str) -> Option<Theme> {
+    match name {
+        "gruvbox-dark" => Some(gruvbox_dark()),
+        "gruvbox-light" => Some(gruvbox_light()),
+        "solarized-dark" => Some(solarized_dark()),
+        _ => None,
+    }
+}
+
+const fn rgb(hex: u32) -> Color {
+    Color::RGBA((hex >> 16) as u8, (hex >> 8) as u8, hex as u8, 255)
+}
+
+fn gruvbox_dark() -> Theme {
+    Theme {
+        name: String::from("gruvbox-dark"),
+        window: rgb(0x000000),
+        background: rgb(0x32302f),
+        foreground: rgb(0xfdf4c1),
+        cursor: rgb(0xffffff),
+        selection: rgb(0x504945),
+        search_match: rgb(0x665c54),
+        gutter_background: rgb(0x3c3836),
+        gutter_foreground: rgb(0x928374),
+        border: rgb(0x646464),
+        border_focused: rgb(0x00ff00),
+        syntax: SyntaxColors {
+            keyword: rgb(0xfb4934),
+            ty: rgb(0xfabd2f),
+            function: rgb(0x8ec07c),
+            string: rgb(0xb8bb26),
+            number: rgb(0xd3869b),
+            constant: rgb(0xd3869b),
+            comment: rgb(0x928374),
+            preprocessor: rgb(0xfe8019),
+            property: rgb(0x83a598),
+            heading: rgb(0xfabd2f),
+            emphasis: rgb(0xd3869b),
+            code: rgb(0x8ec07c),
+            link: rgb(0x83a598),
+            punctuation: rgb(0xfe8019),
+        },
+    }
+}
+
+fn gruvbox_light() -> Theme {
+    Theme {
+        name: String::from("gruvbox-light"),
+        window: rgb(0xd5c4a1),
+        background: rgb(0xfbf1c7),
+        foreground: rgb(0x3c3836),
+        cursor: rgb(0x3c3836),
+        selection: rgb(0xd5c4a1),
+        search_match: rgb(0xbdae93),
+        gutter_background: rgb(0xebdbb2),
+        gutter_foreground: rgb(0x928374),
+        border: rgb(0xbdae93),
+        border_focused: rgb(0x79740e),
+        syntax: SyntaxColors {
+            keyword: rgb(0x9d0006),
+            ty: rgb(0xb57614),
+            function: rgb(0x427b58),
+            string: rgb(0x79740e),
+            number: rgb(0x8f3f71),
+            constant: rgb(0x8f3f71),
+            comment: rgb(0x928374),
+            preprocessor: rgb(0xaf3a03),
+            property: rgb(0x076678),
+            heading: rgb(0xb57614),
+            emphasis: rgb(0x8f3f71),
+            code: rgb(0x427b58),
+            link: rgb(0x076678),
+            punctuation: rgb(0xaf3a03),
+        },
+    }
+}
+
+fn solarized_dark() -> Theme {
+    Theme {
+        name: String::from("solarized-dark"),
+        window: rgb(0x00212b),
+        background: rgb(0x002b36),
+        foreground: rgb(0x839496),
+        cursor: rgb(0x93a1a1),
+        selection: rgb(0x073642),
+        search_match: rgb(0x586e75),
+        gutter_background: rgb(0x073642),
+        gutter_foreground: rgb(0x586e75),
+        border: rgb(0x586e75),
+        border_focused: rgb(0x268bd2),
+        syntax: SyntaxColors {
+            keyword: rgb(0x859900),
+            ty: rgb(0xb58900),
+            function: rgb(0x268bd2),
+            string: rgb(0x2aa198),
+            number: rgb(0xd33682),
+            constant: rgb(0xd33682),
+            comment: rgb(0x586e75),
+            preprocessor: rgb(0xcb4b16),
+            property: rgb(0x6c71c4),
+            heading: rgb(0xb58900),
+            emphasis: rgb(0xd33682),
+            code: rgb(0x2aa198),
+            link: rgb(0x6c71c4),
+            punctuation: rgb(0xcb4b16),
+        },
+    }
+}
+
+impl Theme {
+    pub fn scope(&self, scope: Scope) -> Color {
+        let syntax = &self.syntax;
+        match scope {
+            Scope::Keyword => syntax.keyword,
+            Scope::Type => syntax.ty,
+            Scope::Function => syntax.function,
+            Scope::String => syntax.string,
+            Scope::Number => syntax.number,
+            Scope::Constant => syntax.constant,
+            Scope::Comment => syntax.comment,
+            Scope::Preprocessor => syntax.preprocessor,
+            Scope::Property => syntax.property,
+            Scope::Heading => syntax.heading,
+            Scope::Emphasis => syntax.emphasis,
+            Scope::Code => syntax.code,
+            Scope::Link => syntax.link,
+            Scope::Punctuation => syntax.punctuation,
+        }
+    }
+
+    /// The theme called `name`: `themes/<name>.toml` in the config
+    /// directory if there is one, otherwise the built-in theme.
+    pub fn load(name: &str) -> Result<Theme, ConfigError> {
+        if let Some(path) = theme_path(name).filter(|p| p.exists()) {
+            let source =
+                std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            let mut theme: Theme =
+                toml::from_str(&source).map_err(|e| ConfigError::Parse(path, e))?;
+            theme.name = String::from(name);
+            return Ok(theme);
+        }
+        builtin(name).ok_or(ConfigError::UnknownTheme(String::from(name)))
+    }
+
+    /// Names of the built-in themes and of the theme files, sorted.
+    pub fn available() -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN.iter().map(|n| String::from(*n)).collect();
+        if let Some(entries) = config_dir().and_then(|d| std::fs::read_dir(d.join("themes")).ok()) {
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if path.extension().is_some_and(|ext| ext == "toml") {
+                    if let Some(stem) = path.file_stem() {
+                        names.push(stem.to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The theme after this one in `available`, wrapping around.
+    pub fn next(&self) -> Result<Theme, ConfigError> {
+        let names = Theme::available();
+        let i = names
+            .iter()
+            .position(|n| *n == self.name)
+            .map_or(0, |i| i + 1);
+        Theme::load(&names[i % names.len()])
+    }
+}
+
+fn theme_path(name: &str) -> Option<PathBuf> {
+    Some(config_dir()?.join("themes").join(format!("{}.toml", name)))
+}
+
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let digits = s
+        .strip_prefix('#')
+        .filter(|d| (d.len() == 6 || d.len() == 8) && d.is_ascii())
+        .ok_or(format!("`{}` is not a #rrggbb color", s))?;
+    let channel = |i: usize| {
+        digits
+            .get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (channel(0), channel(2), channel(4), channel(6)) {
+        (Some(r), Some(g), Some(b), a) if digits.len() == 6 || a.is_some() => {
+            Ok(Color::RGBA(r, g, b, a.unwrap_or(255)))
+        }
+        _ => Err(format!("`{}` is not a #rrggbb color", s)),
+    }
+}
+
+fn hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_color(&s).map_err(serde::de::Error::custom)
+}
+
+fn hex_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+    hex(deserializer).map(Some)
+}
@@ -0,0 +1,465 @@
+use crate::buffer::Buffer;
+
+/// Largest count a command takes. Motions and pastes repeat once per
+/// count, so a runaway count would hang the editor.
+const MAX_COUNT: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+    /// Typing an ex command after `:`.
+    Command(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    /// `gg` and `G`, to the given line or to the first and last line.
+    FirstLine,
+    LastLine,
+}
+
+impl Motion {
+    /// Moves over whole lines, so operators act on complete lines.
+    pub fn linewise(&self) -> bool {
+        matches!(
+            self,
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine
+        )
+    }
+
+    /// Includes the char it lands on when used with an operator.
+    pub fn inclusive(&self) -> bool {
+        matches!(self, Motion::WordEnd | Motion::LineEnd)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InsertAt {
+    Cursor,
+    AfterCursor,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Move(Motion, Option<usize>),
+    Operate(Operator, Motion, Option<usize>),
+    /// `dd`, `cc`, `yy`: the operator over `count` lines.
+    OperateLines(Operator, usize),
+    /// `d`, `c` or `y` on the visual selection.
+    OperateSelection(Operator),
+    DeleteChar(usize),
+    Paste(usize),
+    Insert(InsertAt),
+    Visual,
+    Normal,
+    Undo,
+    Repeat,
+    Ex(String),
+}
+
+impl Action {
+    /// Changes the buffer, and so is what `.` repeats.
+    fn is_change(&self) -> bool {
+        match self {
+            Action::Operate(op, ..)
+            | Action::OperateLines(op, _)
+            | Action::OperateSelection(op) => *op != Operator::Yank,
+            Action::DeleteChar(_) | Action::Paste(_) | Action::Insert(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Key presses the modal layer understands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Char(char),
+    Escape,
+    Enter,
+    Backspace,
+}
+
+/// Modal editing state of a `TextArea`: the current mode plus whatever
+/// part of a command (count, operator, `g` prefix) has been typed so far.
+pub struct Vim {
+    pub mode: Mode,
+    count: Option<usize>,
+    operator: Option<(Operator, Option<usize>)>,
+    g_prefix: bool,
+    /// Last yanked or deleted text, and whether it was whole lines.
+    pub register: String,
+    pub register_linewise: bool,
+    /// Last change and the text typed in the insert session that followed,
+    /// for `.`.
+    pub last_change: Option<(Action, String)>,
+    recording: Option<(Action, String)>,
+}
+
+impl Default for Vim {
+    fn default() -> Self {
+        Vim::new()
+    }
+}
+
+impl Vim {
+    pub fn new() -> Self {
+        Vim {
+            mode: Mode::Normal,
+            count: None,
+            operator: None,
+            g_prefix: false,
+            register: String::new(),
+            register_linewise: false,
+            last_change: None,
+            recording: None,
+        }
+    }
+
+    /// Text drawn where the `line:col` info goes.
+    pub fn status(&self) -> String {
+        match &self.mode {
+            Mode::Normal => String::from("NORMAL"),
+            Mode::Insert => String::from("-- INSERT --"),
+            Mode::Visual => String::from("-- VISUAL --"),
+            Mode::Command(cmd) => format!(":{}", cmd),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = None;
+        self.operator = None;
+        self.g_prefix = false;
+    }
+
+    /// Feeds one key in Normal, Visual or Command mode. Returns the action
+    /// once a full command has been typed.
+    pub fn key(&mut self, key: Key) -> Option<Action> {
+        if let Mode::Command(cmd) = &mut self.mode {
+            match key {
+                Key::Char(c) => cmd.push(c),
+                Key::Backspace if !cmd.is_empty() => {
+                    cmd.pop();
+                }
+                Key::Enter => {
+                    let cmd = std::mem::take(cmd);
+                    self.mode = Mode::Normal;
+                    return Some(Action::Ex(cmd));
+                }
+                _ => self.mode = Mode::Normal,
+            }
+            return None;
+        }
+
+        let c = match key {
+            Key::Char(c) => c,
+            Key::Escape => {
+                self.reset();
+                return Some(Action::Normal);
+            }
+            Key::Enter => 'j',
+            Key::Backspace => 'h',
+        };
+
+        if c.is_ascii_digit() && (c != '0' || self.count.is_some()) {
+            let digit = c.to_digit(10).unwrap() as usize;
+            let count = self.count.unwrap_or(0).saturating_mul(10);
+            self.count = Some(count.saturating_add(digit).min(MAX_COUNT));
+            return None;
+        }
+
+        let g_prefix = std::mem::take(&mut self.g_prefix);
+        let motion = match c {
+            'h' => Some(Motion::Left),
+            'l' | ' ' => Some(Motion::Right),
+            'j' => Some(Motion::Down),
+            'k' => Some(Motion::Up),
+            'w' => Some(Motion::WordForward),
+            'b' => Some(Motion::WordBackward),
+            'e' => Some(Motion::WordEnd),
+            '0' => Some(Motion::LineStart),
+            '$' => Some(Motion::LineEnd),
+            'G' => Some(Motion::LastLine),
+            'g' if g_prefix => Some(Motion::FirstLine),
+            _ => None,
+        };
+        if let Some(motion) = motion {
+            let count = self.count.take();
+            let action = match self.operator.take() {
+                Some((op, op_count)) => {
+                    let count = match (op_count, count) {
+                        (Some(a), Some(b)) => Some(a.saturating_mul(b).min(MAX_COUNT)),
+                        (a, b) => a.or(b),
+                    };
+                    Action::Operate(op, motion, count)
+                }
+                None => Action::Move(motion, count),
+            };
+            return self.finish(action);
+        }
+
+        let operator = match c {
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            _ => None,
+        };
+        if let Some(op) = operator {
+            if self.mode == Mode::Visual {
+                self.reset();
+                return self.finish(Action::OperateSelection(op));
+            }
+            match self.operator.take() {
+                Some((pending, count)) if pending == op => {
+                    let count = count
+                        .unwrap_or(1)
+                        .saturating_mul(self.count.take().unwrap_or(1));
+                    let count = count.min(MAX_COUNT);
+                    return self.finish(Action::OperateLines(op, count));
+                }
+                _ => self.operator = Some((op, self.count.take())),
+            }
+            return None;
+        }
+
+        let count = self.count.unwrap_or(1);
+        let action = match c {
+            'g' => {
+                self.g_prefix = true;
+                return None;
+            }
+            'x' => Action::DeleteChar(count),
+            'p' => Action::Paste(count),
+            'i' => Action::Insert(InsertAt::Cursor),
+            'a' => Action::Insert(InsertAt::AfterCursor),
+            'I' => Action::Insert(InsertAt::LineStart),
+            'A' => Action::Insert(InsertAt::LineEnd),
+            'o' => Action::Insert(InsertAt::LineBelow),
+            'O' => Action::Insert(InsertAt::LineAbove),
+            'v' if self.mode == Mode::Visual => Action::Normal,
+            'v' => Action::Visual,
+            'u' => Action::Undo,
+            '.' => Action::Repeat,
+            ':' => {
+                self.reset();
+                self.mode = Mode::Command(String::new());
+                return None;
+            }
+            _ => {
+                self.reset();
+                return None;
+            }
+        };
+        self.finish(action)
+    }
+
+    fn finish(&mut self, action: Action) -> Option<Action> {
+        self.reset();
+        if action.is_change() {
+            self.recording = Some((action.clone(), String::new()));
+            self.last_change = self.recording.clone();
+        }
+        Some(action)
+    }
+
+    /// Notes text typed in Insert mode so `.` can replay it.
+    pub fn record_insert(&mut self, text: &str) {
+        if let Some((_, typed)) = self.recording.as_mut() {
+            typed.push_str(text);
+        }
+    }
+
+    pub fn record_backspace(&mut self) {
+        if let Some((_, typed)) = self.recording.as_mut() {
+            typed.pop();
+        }
+    }
+
+    /// Back to Normal mode, closing the change that was being recorded.
+    pub fn leave_insert(&mut self) {
+        self.mode = Mode::Normal;
+        if let Some(change) = self.recording.take() {
+            self.last_change = Some(change);
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Space,
+    Word,
+    Punct,
+}
+
+fn class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Space
+    } else if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punct
+    }
+}
+
+/// `w`: start of the next word.
+pub fn word_forward(buffer: &Buffer, mut idx: usize) -> usize {
+    let len = buffer.len_chars();
+    if idx >= len {
+        return idx;
+    }
+    let start = class(buffer.char(idx));
+    while idx < len && class(buffer.char(idx)) == start && start != CharClass::Space {
+        idx += 1;
+    }
+    while idx < len && class(buffer.char(idx)) == CharClass::Space {
+        idx += 1;
+    }
+    idx.min(len.saturating_sub(1))
+}
+
+/// `b`: start of the current or previous word.
+pub fn word_backward(buffer: &Buffer, mut idx: usize) -> usize {
+    while idx > 0 && class(buffer.char(idx - 1)) == CharClass::Space {
+        idx -= 1;
+    }
+    if idx == 0 {
+        return 0;
+    }
+    let run = class(buffer.char(idx - 1));
+    while idx > 0 && class(buffer.char(idx - 1)) == run {
+        idx -= 1;
+    }
+    idx
+}
+
+/// `e`: end of the current or next word.
+pub fn word_end(buffer: &Buffer, idx: usize) -> usize {
+    let len = buffer.len_chars();
+    let mut idx = idx + 1;
+    while idx < len && class(buffer.char(idx)) == CharClass::Space {
+        idx += 1;
+    }
+    if idx >= len {
+        return len.saturating_sub(1);
+    }
+    let run = class(buffer.char(idx));
+    while idx + 1 < len && class(buffer.char(idx + 1)) == run {
+        idx += 1;
+    }
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `keys` one at a time and returns the last action.
+    fn feed(vim: &mut Vim, keys: &str) -> Option<Action> {
+        keys.chars().fold(None, |_, c| vim.key(Key::Char(c)))
+    }
+
+    #[test]
+    fn counts() {
+        let mut vim = Vim::new();
+        assert_eq!(
+            feed(&mut vim, "3w"),
+            Some(Action::Move(Motion::WordForward, Some(3)))
+        );
+        assert_eq!(
+            feed(&mut vim, "0"),
+            Some(Action::Move(Motion::LineStart, None))
+        );
+        assert_eq!(
+            feed(&mut vim, "10j"),
+            Some(Action::Move(Motion::Down, Some(10)))
+        );
+        // Counts before the operator and the motion multiply
+        assert_eq!(
+            feed(&mut vim, "2d3w"),
+            Some(Action::Operate(
+                Operator::Delete,
+                Motion::WordForward,
+                Some(6)
+            ))
+        );
+        assert_eq!(
+            feed(&mut vim, "3dd"),
+            Some(Action::OperateLines(Operator::Delete, 3))
+        );
+        assert_eq!(
+            feed(&mut vim, "2y4y"),
+            Some(Action::OperateLines(Operator::Yank, 8))
+        );
+        assert_eq!(feed(&mut vim, "x"), Some(Action::DeleteChar(1)));
+    }
+
+    #[test]
+    fn caps_huge_counts() {
+        let mut vim = Vim::new();
+        assert_eq!(
+            feed(&mut vim, "99999999999999999999d"),
+            None,
+            "the operator waits for a motion"
+        );
+        assert_eq!(
+            feed(&mut vim, "99999999999999999999w"),
+            Some(Action::Operate(
+                Operator::Delete,
+                Motion::WordForward,
+                Some(MAX_COUNT)
+            ))
+        );
+        assert_eq!(
+            feed(&mut vim, "99999999999999999999dd"),
+            Some(Action::OperateLines(Operator::Delete, MAX_COUNT))
+        );
+        assert_eq!(
+            feed(&mut vim, "99999999999999999999p"),
+            Some(Action::Paste(MAX_COUNT))
+        );
+    }
+
+    #[test]
+    fn word_motions() {
+        let buffer = Buffer::new("foo.bar  baz\n  qux");
+        assert_eq!(word_forward(&buffer, 0), 3);
+        assert_eq!(word_forward(&buffer, 3), 4);
+        assert_eq!(word_forward(&buffer, 4), 9);
+        assert_eq!(word_forward(&buffer, 9), 15);
+        assert_eq!(word_forward(&buffer, 16), 17, "stops on the last char");
+
+        assert_eq!(word_backward(&buffer, 15), 9);
+        assert_eq!(word_backward(&buffer, 10), 9);
+        assert_eq!(word_backward(&buffer, 9), 4);
+        assert_eq!(word_backward(&buffer, 4), 3);
+        assert_eq!(word_backward(&buffer, 2), 0);
+
+        assert_eq!(word_end(&buffer, 0), 2);
+        assert_eq!(word_end(&buffer, 2), 3);
+        assert_eq!(word_end(&buffer, 3), 6);
+        assert_eq!(word_end(&buffer, 6), 11);
+        assert_eq!(word_end(&buffer, 11), 17);
+        assert_eq!(word_end(&buffer, 17), 17);
+    }
+}